@@ -0,0 +1,170 @@
+use std::fmt;
+
+use crate::prelude::*;
+
+// Keep track of the offsets to index the data in an array.
+// I'm really happy with how this turned out to make book-keeping easier.
+// A macro might make this better though.
+pub(crate) const ALLOCATION_ID_RANGE: Range = next_range::<Address>(0..0);
+pub(crate) const FEE_RANGE: Range = next_range::<U256>(ALLOCATION_ID_RANGE);
+pub(crate) const RECEIPT_ID_RANGE: Range = next_range::<ReceiptId>(FEE_RANGE);
+pub(crate) const SIGNATURE_RANGE: Range = next_range::<Signature>(RECEIPT_ID_RANGE);
+pub(crate) const UNLOCKED_FEE_RANGE: Range = next_range::<U256>(SIGNATURE_RANGE);
+pub const BORROWED_RECEIPT_LEN: usize = UNLOCKED_FEE_RANGE.end;
+/// The part of a borrowed receipt which is sent to the Indexer, and which
+/// is later folded into a voucher.
+const RECEIPT_RANGE: Range = FEE_RANGE.start..SIGNATURE_RANGE.end;
+
+/// A receipt committed by a [`ReceiptPool`](crate::ReceiptPool), which must
+/// be released back into the pool once the query it pays for completes.
+///
+/// The wire format is: [allocation_id, fee, receipt_id, signature, unlocked_fee]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BorrowedReceipt {
+    bytes: [u8; BORROWED_RECEIPT_LEN],
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum BorrowedReceiptError {
+    InvalidLength,
+}
+
+impl std::error::Error for BorrowedReceiptError {}
+
+impl fmt::Display for BorrowedReceiptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength => write!(f, "Invalid borrowed receipt length"),
+        }
+    }
+}
+
+impl BorrowedReceipt {
+    pub(crate) fn new(
+        allocation_id: &Address,
+        fee: U256,
+        receipt_id: &ReceiptId,
+        signature: &Signature,
+        unlocked_fee: U256,
+    ) -> Self {
+        let mut bytes = [0; BORROWED_RECEIPT_LEN];
+        bytes[ALLOCATION_ID_RANGE].copy_from_slice(allocation_id);
+        bytes[FEE_RANGE].copy_from_slice(&to_be_bytes(fee));
+        bytes[RECEIPT_ID_RANGE].copy_from_slice(receipt_id);
+        bytes[SIGNATURE_RANGE].copy_from_slice(signature);
+        bytes[UNLOCKED_FEE_RANGE].copy_from_slice(&to_be_bytes(unlocked_fee));
+        Self { bytes }
+    }
+
+    pub fn allocation_id(&self) -> &Address {
+        self.bytes[ALLOCATION_ID_RANGE].try_into().unwrap()
+    }
+
+    /// The fee the receipt is worth if the query succeeds. This includes
+    /// the unlocked fee.
+    pub fn fee(&self) -> U256 {
+        U256::from_big_endian(&self.bytes[FEE_RANGE])
+    }
+
+    pub fn receipt_id(&self) -> &ReceiptId {
+        self.bytes[RECEIPT_ID_RANGE].try_into().unwrap()
+    }
+
+    pub fn signature(&self) -> &Signature {
+        self.bytes[SIGNATURE_RANGE].try_into().unwrap()
+    }
+
+    /// The fee the receipt was worth before it was borrowed.
+    pub fn unlocked_fee(&self) -> U256 {
+        U256::from_big_endian(&self.bytes[UNLOCKED_FEE_RANGE])
+    }
+
+    /// The 112 byte receipt: [fee, receipt_id, signature]. This is the form
+    /// in which receipts are passed to `receipts_to_voucher`.
+    pub fn receipt(&self) -> &[u8] {
+        &self.bytes[RECEIPT_RANGE]
+    }
+
+    /// The part of the receipt covered by the signature.
+    pub(crate) fn signed_message(&self) -> &[u8] {
+        &self.bytes[ALLOCATION_ID_RANGE.start..RECEIPT_ID_RANGE.end]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl AsRef<[u8]> for BorrowedReceipt {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl TryFrom<&[u8]> for BorrowedReceipt {
+    type Error = BorrowedReceiptError;
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| BorrowedReceiptError::InvalidLength)?;
+        Ok(Self { bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::PublicKey;
+
+    use super::*;
+    use crate::{receipts_to_voucher, tests::*, ReceiptPool};
+
+    #[test]
+    fn accessors() {
+        let mut pool = ReceiptPool::new(bytes(7));
+        let borrow = pool.commit(&test_signer(), 5.into()).unwrap();
+
+        let parsed = BorrowedReceipt::try_from(borrow.as_bytes()).unwrap();
+        assert_eq!(parsed, borrow);
+        assert_eq!(parsed.allocation_id(), &bytes(7));
+        assert_eq!(parsed.fee(), 5.into());
+        assert_eq!(parsed.unlocked_fee(), 0.into());
+        assert_eq!(parsed.as_bytes().len(), BORROWED_RECEIPT_LEN);
+        assert_eq!(parsed.receipt().len(), 112);
+        assert_eq!(&parsed.receipt()[32..47], parsed.receipt_id());
+
+        let signature = sign(parsed.signed_message(), &test_signer()).unwrap();
+        assert_eq!(parsed.signature(), &signature);
+    }
+
+    #[test]
+    fn invalid_length() {
+        let borrow = ReceiptPool::new(bytes(7))
+            .commit(&test_signer(), 5.into())
+            .unwrap();
+        let mut bytes = borrow.as_bytes().to_vec();
+        assert_eq!(
+            BorrowedReceipt::try_from(&bytes[1..]),
+            Err(BorrowedReceiptError::InvalidLength)
+        );
+        bytes.push(0);
+        assert_eq!(
+            BorrowedReceipt::try_from(&bytes[..]),
+            Err(BorrowedReceiptError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn receipt_folds_into_voucher() {
+        let borrow = ReceiptPool::new(bytes(7))
+            .commit(&test_signer(), 5.into())
+            .unwrap();
+        let voucher = receipts_to_voucher(
+            &bytes(7),
+            &PublicKey::from_secret_key(&SECP256K1, &test_signer()),
+            &test_signer(),
+            borrow.receipt(),
+        )
+        .unwrap();
+        assert_eq!(voucher.fees, 5.into());
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{
+    pool::write_commitment, prelude::*, BorrowFail, BorrowedReceipt, PoolStats, QueryStatus,
+    ReceiptPool, ReceiptSigner, ReleaseError,
+};
+
+/// A [`ReceiptPool`] which can be shared between threads.
+///
+/// The lock is only held while a receipt is picked from (or returned to)
+/// the pool. Signing the commitment, which dominates the cost of a commit,
+/// happens with the lock released.
+#[derive(Debug)]
+pub struct ConcurrentReceiptPool {
+    allocation: Address,
+    pool: Mutex<ReceiptPool>,
+}
+
+impl ConcurrentReceiptPool {
+    pub fn new(allocation: Address) -> Self {
+        Self::from_pool(ReceiptPool::new(allocation))
+    }
+
+    pub fn from_pool(pool: ReceiptPool) -> Self {
+        Self {
+            allocation: pool.allocation,
+            pool: Mutex::new(pool),
+        }
+    }
+
+    pub fn into_inner(self) -> ReceiptPool {
+        self.pool
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn allocation(&self) -> &Address {
+        &self.allocation
+    }
+
+    /// Lock the pool for operations which are not exposed on this type, such
+    /// as taking a snapshot or reclaiming expired borrows.
+    pub fn lock(&self) -> MutexGuard<'_, ReceiptPool> {
+        // The pool is consistent between method calls, so a panic while
+        // holding the lock elsewhere doesn't leave it in a broken state.
+        self.pool.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn commit(
+        &self,
+        signer: &(impl ReceiptSigner + ?Sized),
+        locked_fee: U256,
+    ) -> Result<BorrowedReceipt, BorrowFail> {
+        let receipt = self.lock().take_receipt(locked_fee)?;
+        write_commitment(&self.allocation, &receipt, locked_fee, signer).inspect_err(|_| {
+            self.lock().cancel_commit(&receipt.receipt_id);
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.lock().stats()
+    }
+
+    pub fn release(
+        &self,
+        borrowed: impl AsRef<[u8]>,
+        status: QueryStatus,
+    ) -> Result<(), ReleaseError> {
+        self.lock().release(borrowed, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ConcurrentReceiptPool>();
+    }
+
+    #[test]
+    fn receipts_are_never_borrowed_twice() {
+        const THREADS: usize = 8;
+        const ITERATIONS: usize = 200;
+
+        let pool = ConcurrentReceiptPool::new(bytes(1));
+        let in_flight = Mutex::new(HashSet::<ReceiptId>::new());
+        let signer = test_signer();
+
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        let borrow = pool.commit(&signer, U256::from(1)).unwrap();
+                        let receipt_id = *borrow.receipt_id();
+                        assert!(
+                            in_flight.lock().unwrap().insert(receipt_id),
+                            "receipt borrowed twice concurrently"
+                        );
+                        thread::yield_now();
+                        in_flight.lock().unwrap().remove(&receipt_id);
+                        pool.release(&borrow, QueryStatus::Success).unwrap();
+                    }
+                });
+            }
+        });
+
+        let pool = pool.into_inner();
+        assert_eq!(pool.borrowed().count(), 0);
+        assert_eq!(pool.known_unlocked_fees(), U256::from(THREADS * ITERATIONS));
+    }
+}
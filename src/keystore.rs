@@ -0,0 +1,502 @@
+use std::{
+    fmt, fs,
+    io::{self, Write as _},
+    path::Path,
+};
+
+use aes::Aes128;
+use ctr::{
+    cipher::{KeyIvInit as _, StreamCipher as _},
+    Ctr128BE,
+};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng as _};
+use secp256k1::SecretKey;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use subtle::ConstantTimeEq as _;
+use tiny_keccak::{Hasher as _, Keccak};
+use zeroize::Zeroizing;
+
+use crate::{hex, prelude::*, ReceiptSigner};
+
+const VERSION: u64 = 3;
+const CIPHER: &str = "aes-128-ctr";
+const DKLEN: usize = 32;
+// Keystores are untrusted input, so the work and memory a kdf may demand are
+// bounded. These are well above what geth writes, and scrypt stays under
+// 1 GiB of memory.
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 8;
+const MAX_SCRYPT_P: u32 = 16;
+const MAX_PBKDF2_ROUNDS: u32 = 10_000_000;
+
+/// The key derivation function which stretches the password of a keystore.
+/// Scrypt is limited to `log_n` of 20, `r` of 8 and `p` of 16, and pbkdf2
+/// to 10 million rounds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeystoreKdf {
+    Scrypt { log_n: u8, r: u32, p: u32 },
+    Pbkdf2 { rounds: u32 },
+}
+
+impl KeystoreKdf {
+    /// The scrypt parameters geth uses by default.
+    pub const STANDARD: Self = Self::Scrypt {
+        log_n: 18,
+        r: 8,
+        p: 1,
+    };
+    /// The scrypt parameters geth uses with `--lightkdf`, which take far
+    /// less time and memory to unlock at the cost of weaker protection.
+    pub const LIGHT: Self = Self::Scrypt {
+        log_n: 12,
+        r: 8,
+        p: 6,
+    };
+}
+
+impl Default for KeystoreKdf {
+    fn default() -> Self {
+        Self::STANDARD
+    }
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    InvalidFormat(String),
+    UnsupportedVersion(u64),
+    UnsupportedCipher(String),
+    UnsupportedKdf(String),
+    InvalidKdfParams,
+    WrongPassword,
+    InvalidKey,
+    AddressMismatch,
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "Failed to access keystore: {}", err),
+            Self::InvalidFormat(err) => write!(f, "Invalid keystore: {}", err),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported keystore version {}", version)
+            }
+            Self::UnsupportedCipher(cipher) => write!(f, "Unsupported keystore cipher {}", cipher),
+            Self::UnsupportedKdf(kdf) => write!(f, "Unsupported keystore kdf {}", kdf),
+            Self::InvalidKdfParams => write!(f, "Invalid keystore kdf parameters"),
+            Self::WrongPassword => write!(f, "Wrong keystore password"),
+            Self::InvalidKey => write!(f, "Keystore does not hold a valid secp256k1 key"),
+            Self::AddressMismatch => write!(f, "Keystore key does not match its address"),
+        }
+    }
+}
+
+impl From<io::Error> for KeystoreError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// The Web3 Secret Storage format, version 3.
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    version: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(alias = "Crypto")]
+    crypto: Crypto,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Crypto {
+    cipher: String,
+    cipherparams: CipherParams,
+    ciphertext: String,
+    kdf: String,
+    kdfparams: Value,
+    mac: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScryptParams {
+    dklen: usize,
+    n: u64,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pbkdf2Params {
+    c: u32,
+    dklen: usize,
+    prf: String,
+    salt: String,
+}
+
+/// Decrypt the key held by a V3 JSON keystore, as written by geth, clef,
+/// foundry and most other Ethereum tooling.
+pub fn decrypt_keystore(json: &str, password: &str) -> Result<SecretKey, KeystoreError> {
+    let keystore: Keystore = parse(serde_json::from_str(json))?;
+    if keystore.version != VERSION {
+        return Err(KeystoreError::UnsupportedVersion(keystore.version));
+    }
+    let crypto = keystore.crypto;
+    if crypto.cipher != CIPHER {
+        return Err(KeystoreError::UnsupportedCipher(crypto.cipher));
+    }
+
+    let (kdf, salt) = match crypto.kdf.as_str() {
+        "scrypt" => {
+            let params: ScryptParams = parse(serde_json::from_value(crypto.kdfparams))?;
+            if params.dklen != DKLEN || !params.n.is_power_of_two() {
+                return Err(KeystoreError::InvalidKdfParams);
+            }
+            let kdf = KeystoreKdf::Scrypt {
+                log_n: params.n.trailing_zeros() as u8,
+                r: params.r,
+                p: params.p,
+            };
+            (kdf, params.salt)
+        }
+        "pbkdf2" => {
+            let params: Pbkdf2Params = parse(serde_json::from_value(crypto.kdfparams))?;
+            if params.dklen != DKLEN || params.prf != "hmac-sha256" {
+                return Err(KeystoreError::InvalidKdfParams);
+            }
+            (KeystoreKdf::Pbkdf2 { rounds: params.c }, params.salt)
+        }
+        _ => return Err(KeystoreError::UnsupportedKdf(crypto.kdf)),
+    };
+    let salt = decode_hex("salt", &salt)?;
+    let iv = decode_hex("iv", &crypto.cipherparams.iv)?;
+    let mut key = Zeroizing::new(decode_hex("ciphertext", &crypto.ciphertext)?);
+    let mac = decode_hex("mac", &crypto.mac)?;
+
+    let derived_key = derive_key(kdf, password, &salt)?;
+    if !bool::from(mac.ct_eq(&keystore_mac(&derived_key, &key))) {
+        return Err(KeystoreError::WrongPassword);
+    }
+    apply_cipher(&derived_key, &iv, &mut key)?;
+    let key = SecretKey::from_slice(&key).map_err(|_| KeystoreError::InvalidKey)?;
+
+    if let Some(address) = keystore.address {
+        if decode_hex("address", &address)? != key.address() {
+            return Err(KeystoreError::AddressMismatch);
+        }
+    }
+    Ok(key)
+}
+
+/// Encrypt the key as a V3 JSON keystore. The salt, iv and id are drawn
+/// from the RNG.
+pub fn encrypt_keystore(
+    key: &SecretKey,
+    password: &str,
+    kdf: KeystoreKdf,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Result<String, KeystoreError> {
+    let mut salt = [0; 32];
+    rng.fill_bytes(&mut salt);
+    let mut iv = [0; 16];
+    rng.fill_bytes(&mut iv);
+    let mut id = [0; 16];
+    rng.fill_bytes(&mut id);
+
+    let derived_key = derive_key(kdf, password, &salt)?;
+    let mut ciphertext = key.secret_bytes();
+    apply_cipher(&derived_key, &iv, &mut ciphertext)?;
+
+    let (kdf, kdfparams) = match kdf {
+        KeystoreKdf::Scrypt { log_n, r, p } => {
+            let params = ScryptParams {
+                dklen: DKLEN,
+                n: 1 << log_n,
+                r,
+                p,
+                salt: hex::encode(&salt),
+            };
+            ("scrypt", serde_json::to_value(params))
+        }
+        KeystoreKdf::Pbkdf2 { rounds } => {
+            let params = Pbkdf2Params {
+                c: rounds,
+                dklen: DKLEN,
+                prf: "hmac-sha256".into(),
+                salt: hex::encode(&salt),
+            };
+            ("pbkdf2", serde_json::to_value(params))
+        }
+    };
+    let keystore = Keystore {
+        version: VERSION,
+        id: Some(uuid_v4(id)),
+        address: Some(hex::encode(&key.address())),
+        crypto: Crypto {
+            cipher: CIPHER.into(),
+            cipherparams: CipherParams {
+                iv: hex::encode(&iv),
+            },
+            ciphertext: hex::encode(&ciphertext),
+            kdf: kdf.into(),
+            kdfparams: kdfparams.unwrap(),
+            mac: hex::encode(&keystore_mac(&derived_key, &ciphertext)),
+        },
+    };
+    Ok(serde_json::to_string(&keystore).unwrap())
+}
+
+/// Read and decrypt a V3 JSON keystore file.
+pub fn load_keystore(path: impl AsRef<Path>, password: &str) -> Result<SecretKey, KeystoreError> {
+    decrypt_keystore(&fs::read_to_string(path)?, password)
+}
+
+/// Encrypt the key and write it to a V3 JSON keystore file. On unix, a new
+/// file is created readable only by its owner, as geth does.
+pub fn save_keystore(
+    path: impl AsRef<Path>,
+    key: &SecretKey,
+    password: &str,
+    kdf: KeystoreKdf,
+) -> Result<(), KeystoreError> {
+    let json = encrypt_keystore(key, password, kdf, &mut StdRng::from_entropy())?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+fn parse<T: DeserializeOwned>(result: serde_json::Result<T>) -> Result<T, KeystoreError> {
+    result.map_err(|err| KeystoreError::InvalidFormat(err.to_string()))
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(value).ok_or_else(|| KeystoreError::InvalidFormat(format!("Invalid {}", field)))
+}
+
+fn derive_key(
+    kdf: KeystoreKdf,
+    password: &str,
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; DKLEN]>, KeystoreError> {
+    let mut derived_key = Zeroizing::new([0; DKLEN]);
+    match kdf {
+        KeystoreKdf::Scrypt { log_n, r, p } => {
+            if log_n > MAX_SCRYPT_LOG_N || r > MAX_SCRYPT_R || p > MAX_SCRYPT_P {
+                return Err(KeystoreError::InvalidKdfParams);
+            }
+            let params = scrypt::Params::new(log_n, r, p, DKLEN)
+                .map_err(|_| KeystoreError::InvalidKdfParams)?;
+            scrypt::scrypt(password.as_bytes(), salt, &params, &mut *derived_key)
+                .map_err(|_| KeystoreError::InvalidKdfParams)?;
+        }
+        KeystoreKdf::Pbkdf2 { rounds } => {
+            if rounds == 0 || rounds > MAX_PBKDF2_ROUNDS {
+                return Err(KeystoreError::InvalidKdfParams);
+            }
+            pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut *derived_key);
+        }
+    }
+    Ok(derived_key)
+}
+
+// The first half of the derived key is the cipher key, and the second half
+// authenticates the ciphertext.
+fn keystore_mac(derived_key: &[u8; DKLEN], ciphertext: &[u8]) -> Bytes32 {
+    let mut hasher = Keccak::v256();
+    hasher.update(&derived_key[16..]);
+    hasher.update(ciphertext);
+    let mut mac = Bytes32::default();
+    hasher.finalize(&mut mac);
+    mac
+}
+
+fn apply_cipher(
+    derived_key: &[u8; DKLEN],
+    iv: &[u8],
+    data: &mut [u8],
+) -> Result<(), KeystoreError> {
+    let mut cipher = Ctr128BE::<Aes128>::new_from_slices(&derived_key[..16], iv)
+        .map_err(|_| KeystoreError::InvalidFormat("Invalid iv".into()))?;
+    cipher.apply_keystream(data);
+    Ok(())
+}
+
+fn uuid_v4(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(&bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    const TEST_KDF: KeystoreKdf = KeystoreKdf::Scrypt {
+        log_n: 10,
+        r: 8,
+        p: 1,
+    };
+
+    // From the Web3 Secret Storage definition.
+    const PBKDF2_KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+            "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+            "kdf": "pbkdf2",
+            "kdfparams": {
+                "c": 262144,
+                "dklen": 32,
+                "prf": "hmac-sha256",
+                "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+            },
+            "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+        },
+        "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+        "version": 3
+    }"#;
+
+    // The test signer, encrypted with the password "testpassword".
+    const SCRYPT_KEYSTORE: &str = r#"{
+        "address": "c61127cdfb5380df4214b0200b9a07c7c49d34f9",
+        "Crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "101112131415161718191a1b1c1d1e1f" },
+            "ciphertext": "55b001b6be0242dabe05a84c4f5e52103278dfc9ad5f1c8fd85fcf771463edfd",
+            "kdf": "scrypt",
+            "kdfparams": {
+                "dklen": 32,
+                "n": 1024,
+                "p": 1,
+                "r": 8,
+                "salt": "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            },
+            "mac": "cbbe6706fc4e352035398ec6da6f024014f9e38e6b99507587c46ee9c34fd5ea"
+        },
+        "version": 3
+    }"#;
+
+    #[test]
+    fn decrypts_keystores() {
+        let key = decrypt_keystore(PBKDF2_KEYSTORE, "testpassword").unwrap();
+        assert_eq!(
+            hex::encode(&key.secret_bytes()),
+            "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d"
+        );
+
+        let key = decrypt_keystore(SCRYPT_KEYSTORE, "testpassword").unwrap();
+        assert_eq!(key, test_signer());
+        assert!(matches!(
+            decrypt_keystore(SCRYPT_KEYSTORE, "wrong"),
+            Err(KeystoreError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for kdf in [TEST_KDF, KeystoreKdf::Pbkdf2 { rounds: 1024 }] {
+            let json = encrypt_keystore(&test_signer(), "password", kdf, &mut rng).unwrap();
+            assert_eq!(decrypt_keystore(&json, "password").unwrap(), test_signer());
+            assert!(matches!(
+                decrypt_keystore(&json, "Password"),
+                Err(KeystoreError::WrongPassword)
+            ));
+        }
+
+        let path = std::env::temp_dir().join(format!("receipts-keystore-{}", std::process::id()));
+        save_keystore(&path, &test_signer(), "password", TEST_KDF).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let key = load_keystore(&path, "password");
+        fs::remove_file(&path).unwrap();
+        assert_eq!(key.unwrap(), test_signer());
+        assert!(matches!(
+            load_keystore(&path, "password"),
+            Err(KeystoreError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_keystores() {
+        let edit = |f: fn(&mut Value)| {
+            let mut keystore: Value = serde_json::from_str(SCRYPT_KEYSTORE).unwrap();
+            f(&mut keystore);
+            decrypt_keystore(&keystore.to_string(), "testpassword").unwrap_err()
+        };
+        assert!(matches!(
+            edit(|k| k["version"] = 4.into()),
+            KeystoreError::UnsupportedVersion(4)
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["cipher"] = "aes-128-cbc".into()),
+            KeystoreError::UnsupportedCipher(_)
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["kdf"] = "argon2".into()),
+            KeystoreError::UnsupportedKdf(_)
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["kdfparams"]["n"] = 1000.into()),
+            KeystoreError::InvalidKdfParams
+        ));
+        // Parameters which would take too long or too much memory to derive.
+        assert!(matches!(
+            edit(|k| k["Crypto"]["kdfparams"]["n"] = (1u64 << 40).into()),
+            KeystoreError::InvalidKdfParams
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["kdfparams"]["r"] = 1024.into()),
+            KeystoreError::InvalidKdfParams
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["kdfparams"]["p"] = u32::MAX.into()),
+            KeystoreError::InvalidKdfParams
+        ));
+        let mut keystore: Value = serde_json::from_str(PBKDF2_KEYSTORE).unwrap();
+        keystore["crypto"]["kdfparams"]["c"] = u32::MAX.into();
+        assert!(matches!(
+            decrypt_keystore(&keystore.to_string(), "testpassword"),
+            Err(KeystoreError::InvalidKdfParams)
+        ));
+        assert!(matches!(
+            edit(|k| k["Crypto"]["mac"] = "zz".into()),
+            KeystoreError::InvalidFormat(_)
+        ));
+        assert!(matches!(
+            edit(|k| k["address"] = "0000000000000000000000000000000000000000".into()),
+            KeystoreError::AddressMismatch
+        ));
+        assert!(matches!(
+            decrypt_keystore("{}", "testpassword"),
+            Err(KeystoreError::InvalidFormat(_))
+        ));
+    }
+}
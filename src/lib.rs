@@ -1,11 +1,60 @@
-pub use pool::{BorrowFail, QueryStatus, ReceiptPool};
+pub use borrowed::{BorrowedReceipt, BorrowedReceiptError, BORROWED_RECEIPT_LEN};
+pub use concurrent::ConcurrentReceiptPool;
+#[cfg(feature = "keystore")]
+pub use keystore::{
+    decrypt_keystore, encrypt_keystore, load_keystore, save_keystore, KeystoreError, KeystoreKdf,
+};
+#[cfg(feature = "mnemonic")]
+pub use mnemonic::{AllocationSigner, IndexerMnemonic, MnemonicError, ALLOCATION_INDEX_LIMIT};
+#[cfg(feature = "async")]
+pub use nonblocking::{
+    combine_partial_vouchers_async, combine_partial_vouchers_with_signers_async,
+    merge_partial_vouchers_async, receipts_to_partial_voucher_async, receipts_to_voucher_async,
+    AsyncReceiptSigner, BlockingSigner,
+};
+pub use pool::{
+    Borrow, BorrowFail, FeeCounter, LifetimeStats, PoolStats, PooledReceipt, QueryStatus,
+    ReceiptPool, ReleaseError,
+};
+pub use pools::ReceiptPools;
+pub use prelude::SignError;
+pub use receipt_id::{
+    ReceiptIdDerivation, ReceiptIdDerivationError, ReceiptIdPrefix, ReceiptIdPrefixError,
+};
+#[cfg(feature = "remote-signer")]
+pub use remote_signer::RemoteSigner;
+pub use selection::{
+    HighestUnlockedFee, LeastRecentlyUsed, LowestUnlockedFee, ReceiptSelection, UniformRandom,
+};
+pub use signer::{recover_address, ReceiptSigner, SignatureError};
+pub use snapshot::{BorrowSnapshot, PoolSnapshot, SnapshotError};
 pub use voucher::{
-    combine_partial_vouchers, receipts_to_partial_voucher, receipts_to_voucher, PartialVoucher,
-    Voucher, VoucherError,
+    combine_partial_vouchers, combine_partial_vouchers_with_signers, merge_partial_vouchers,
+    merge_partial_vouchers_with_signers, receipts_report, receipts_to_partial_voucher,
+    receipts_to_voucher, verify_partial_voucher, verify_voucher, ExpectedSigner, FaultyReceipt,
+    PartialVoucher, ReceiptFault, ReceiptReport, ReceiptVerification, SignatureValidation, Voucher,
+    VoucherError,
 };
 
+mod borrowed;
+mod concurrent;
+#[cfg(any(feature = "keystore", feature = "remote-signer"))]
+mod hex;
+#[cfg(feature = "keystore")]
+mod keystore;
+#[cfg(feature = "mnemonic")]
+mod mnemonic;
+#[cfg(feature = "async")]
+mod nonblocking;
 mod pool;
+mod pools;
 mod prelude;
+mod receipt_id;
+#[cfg(feature = "remote-signer")]
+mod remote_signer;
+mod selection;
+mod signer;
+mod snapshot;
 mod voucher;
 
 #[cfg(test)]
@@ -0,0 +1,284 @@
+use std::{fmt, ops::Range};
+
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac as _};
+use secp256k1::{PublicKey, Scalar, SecretKey};
+use sha2::Sha512;
+
+use crate::{prelude::*, ReceiptSigner};
+
+/// Indexers try allocation ids at indices below this for each deployment and
+/// epoch, so [`IndexerMnemonic::find_allocation_signer`] searches no further.
+pub const ALLOCATION_INDEX_LIMIT: u32 = 100;
+
+const HARDENED: u32 = 1 << 31;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MnemonicError {
+    InvalidMnemonic(String),
+    InvalidPath,
+    InvalidChild,
+}
+
+impl std::error::Error for MnemonicError {}
+
+impl fmt::Display for MnemonicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMnemonic(err) => write!(f, "Invalid mnemonic: {}", err),
+            Self::InvalidPath => write!(f, "Invalid derivation path"),
+            Self::InvalidChild => write!(f, "Derivation path leads to an invalid key"),
+        }
+    }
+}
+
+/// A BIP-32 extended private key.
+#[derive(Clone)]
+struct ExtendedKey {
+    key: SecretKey,
+    chain_code: Bytes32,
+}
+
+impl ExtendedKey {
+    fn master(seed: &[u8]) -> Result<Self, MnemonicError> {
+        Self::from_hmac(b"Bitcoin seed", &[seed], |il| {
+            SecretKey::from_slice(il).map_err(|_| MnemonicError::InvalidChild)
+        })
+    }
+
+    fn child(&self, index: u32) -> Result<Self, MnemonicError> {
+        let index = index.to_be_bytes();
+        let parent = if index[0] & 0x80 == 0 {
+            PublicKey::from_secret_key(&SECP256K1, &self.key)
+                .serialize()
+                .to_vec()
+        } else {
+            let mut parent = vec![0];
+            parent.extend_from_slice(&self.key.secret_bytes());
+            parent
+        };
+        Self::from_hmac(&self.chain_code, &[&parent, &index], |il| {
+            let tweak = Scalar::from_be_bytes(il.try_into().unwrap())
+                .map_err(|_| MnemonicError::InvalidChild)?;
+            self.key
+                .add_tweak(&tweak)
+                .map_err(|_| MnemonicError::InvalidChild)
+        })
+    }
+
+    // Split HMAC-SHA512(key, data) into the key from the left half and the
+    // chain code from the right half.
+    fn from_hmac(
+        key: &[u8],
+        data: &[&[u8]],
+        to_key: impl FnOnce(&[u8]) -> Result<SecretKey, MnemonicError>,
+    ) -> Result<Self, MnemonicError> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+        for data in data {
+            mac.update(data);
+        }
+        let output = mac.finalize().into_bytes();
+        Ok(Self {
+            key: to_key(&output[..32])?,
+            chain_code: output[32..].try_into().unwrap(),
+        })
+    }
+
+    fn derive(&self, path: impl IntoIterator<Item = u32>) -> Result<Self, MnemonicError> {
+        path.into_iter()
+            .try_fold(self.clone(), |node, index| node.child(index))
+    }
+}
+
+/// The BIP-39 mnemonic an indexer derives its allocation signers from.
+///
+/// The allocation signer for a deployment in an epoch is at the BIP-32 path
+/// `m/{epoch}/{b0}/{b1}/.../{index}`, where `b0, b1, ...` are the bytes of
+/// the deployment id string (such as `Qm...`) written as decimal numbers.
+/// The allocation id is the address of the signer.
+#[derive(Clone)]
+pub struct IndexerMnemonic {
+    root: ExtendedKey,
+}
+
+impl fmt::Debug for IndexerMnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexerMnemonic").finish_non_exhaustive()
+    }
+}
+
+/// An allocation signer found by [`IndexerMnemonic::find_allocation_signer`].
+#[derive(Clone)]
+pub struct AllocationSigner {
+    pub epoch: u32,
+    pub index: u32,
+    pub key: SecretKey,
+}
+
+impl fmt::Debug for AllocationSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllocationSigner")
+            .field("epoch", &self.epoch)
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl IndexerMnemonic {
+    pub fn new(phrase: &str) -> Result<Self, MnemonicError> {
+        Self::with_passphrase(phrase, "")
+    }
+
+    /// Use a mnemonic protected by a BIP-39 passphrase.
+    pub fn with_passphrase(phrase: &str, passphrase: &str) -> Result<Self, MnemonicError> {
+        let mnemonic = Mnemonic::parse(phrase)
+            .map_err(|err| MnemonicError::InvalidMnemonic(err.to_string()))?;
+        Ok(Self {
+            root: ExtendedKey::master(&mnemonic.to_seed(passphrase))?,
+        })
+    }
+
+    /// Derive the key at a BIP-32 path such as `m/44'/60'/0'/0/0`.
+    pub fn derive_path(&self, path: &str) -> Result<SecretKey, MnemonicError> {
+        let mut components = path.split('/');
+        if components.next() != Some("m") {
+            return Err(MnemonicError::InvalidPath);
+        }
+        let path = components
+            .map(|component| {
+                let (index, hardened) = match component.strip_suffix(['\'', 'h', 'H']) {
+                    Some(index) => (index, HARDENED),
+                    None => (component, 0),
+                };
+                match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | hardened),
+                    _ => Err(MnemonicError::InvalidPath),
+                }
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+        Ok(self.root.derive(path)?.key)
+    }
+
+    pub fn allocation_signer(
+        &self,
+        epoch: u32,
+        deployment: &str,
+        index: u32,
+    ) -> Result<SecretKey, MnemonicError> {
+        let deployment = self.deployment_node(epoch, deployment)?;
+        Ok(deployment.child(non_hardened(index)?)?.key)
+    }
+
+    /// Find the signer for an allocation id, searching the epochs and every
+    /// index below [`ALLOCATION_INDEX_LIMIT`].
+    pub fn find_allocation_signer(
+        &self,
+        deployment: &str,
+        epochs: Range<u32>,
+        allocation_id: &Address,
+    ) -> Result<Option<AllocationSigner>, MnemonicError> {
+        for epoch in epochs {
+            let node = self.deployment_node(epoch, deployment)?;
+            for index in 0..ALLOCATION_INDEX_LIMIT {
+                let key = match node.child(index) {
+                    Ok(node) => node.key,
+                    Err(MnemonicError::InvalidChild) => continue,
+                    Err(err) => return Err(err),
+                };
+                if &key.address() == allocation_id {
+                    return Ok(Some(AllocationSigner { epoch, index, key }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // The node at m/{epoch}/{deployment bytes}, which is shared by every
+    // allocation signer for the deployment in the epoch.
+    fn deployment_node(&self, epoch: u32, deployment: &str) -> Result<ExtendedKey, MnemonicError> {
+        let path = [non_hardened(epoch)?]
+            .into_iter()
+            .chain(deployment.bytes().map(u32::from));
+        self.root.derive(path)
+    }
+}
+
+fn non_hardened(index: u32) -> Result<u32, MnemonicError> {
+    if index >= HARDENED {
+        return Err(MnemonicError::InvalidPath);
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hex::{FromHex as _, ToHex as _};
+
+    use super::*;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+    const DEPLOYMENT: &str = "QmWmyoMoctfbAaiEs2G46gpeUmhqFRDW6KWo64y5r581Vz";
+
+    fn address(hex: &str) -> Address {
+        hex.from_hex::<Vec<u8>>().unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn derives_standard_paths() {
+        let mnemonic = IndexerMnemonic::new(PHRASE).unwrap();
+        let key = mnemonic.derive_path("m/44'/60'/0'/0/0").unwrap();
+        assert_eq!(
+            key.address(),
+            address("9858effd232b4033e47d90003d41ec34ecaeda94")
+        );
+
+        for path in ["", "44'/60'", "m/x", "m/2147483648"] {
+            assert_eq!(mnemonic.derive_path(path), Err(MnemonicError::InvalidPath));
+        }
+        assert!(matches!(
+            IndexerMnemonic::new("abandon abandon"),
+            Err(MnemonicError::InvalidMnemonic(_))
+        ));
+    }
+
+    #[test]
+    fn derives_allocation_signers() {
+        let mnemonic = IndexerMnemonic::new(PHRASE).unwrap();
+        let key = mnemonic.allocation_signer(5, DEPLOYMENT, 3).unwrap();
+        assert_eq!(
+            key.secret_bytes().to_hex::<String>(),
+            "dff3cdcac86bb70d3132abc8bc3f7f31b9f409e893d776c3d6a0c846dc467c93"
+        );
+        let path = format!(
+            "m/5/{}/3",
+            DEPLOYMENT
+                .bytes()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+        assert_eq!(mnemonic.derive_path(&path), Ok(key));
+    }
+
+    #[test]
+    fn finds_allocation_signers() {
+        let mnemonic = IndexerMnemonic::new(PHRASE).unwrap();
+        let allocation_id = address("4f7b17f4385a25d03d34438dfc69b1cfb934f534");
+        let signer = mnemonic
+            .find_allocation_signer(DEPLOYMENT, 0..10, &allocation_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!((signer.epoch, signer.index), (5, 3));
+        assert_eq!(signer.key.address(), allocation_id);
+
+        assert!(mnemonic
+            .find_allocation_signer(DEPLOYMENT, 0..5, &allocation_id)
+            .unwrap()
+            .is_none());
+        let other = IndexerMnemonic::with_passphrase(PHRASE, "passphrase").unwrap();
+        assert!(other
+            .find_allocation_signer(DEPLOYMENT, 5..6, &allocation_id)
+            .unwrap()
+            .is_none());
+    }
+}
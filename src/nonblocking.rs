@@ -0,0 +1,425 @@
+//! Async versions of the operations which sign or verify, for use from a
+//! Tokio runtime. Verifying receipts and partial vouchers is CPU heavy, so
+//! it runs on the blocking thread pool instead of stalling the runtime.
+//! Signing goes through [`AsyncReceiptSigner`], so that keys may be held
+//! by services which are only reachable asynchronously. A synchronous
+//! [`ReceiptSigner`] is used through [`BlockingSigner`].
+
+use std::{future::Future, panic, sync::Arc};
+
+use secp256k1::PublicKey;
+
+use crate::{
+    pool::{commitment_message, to_commitment},
+    prelude::*,
+    voucher::{
+        partial_voucher_bounds, partial_voucher_message, receipt_id_bounds,
+        verify_partial_vouchers, verify_receipts, voucher_message,
+    },
+    BorrowFail, BorrowedReceipt, ConcurrentReceiptPool, ExpectedSigner, PartialVoucher,
+    PooledReceipt, ReceiptPool, ReceiptSigner, ReceiptVerification, Voucher, VoucherError,
+};
+
+/// A [`ReceiptSigner`] which signs asynchronously. Wrap a `ReceiptSigner`
+/// in a [`BlockingSigner`] to use it here.
+pub trait AsyncReceiptSigner: Sync {
+    /// Sign the keccak256 digest of a message. The signature is
+    /// [r, s, v] with the recovery id `v` as 27 or 28.
+    fn sign_digest(
+        &self,
+        digest: &Bytes32,
+    ) -> impl Future<Output = Result<Signature, SignError>> + Send;
+
+    fn public_key(&self) -> PublicKey;
+
+    /// The Ethereum address of the signer.
+    fn address(&self) -> Address {
+        to_address(&self.public_key())
+    }
+}
+
+/// An [`AsyncReceiptSigner`] which runs a [`ReceiptSigner`] on the blocking
+/// thread pool, since signing may block, such as on a request to a remote
+/// signing service. Must be used from within a Tokio runtime.
+#[derive(Debug)]
+pub struct BlockingSigner<S>(Arc<S>);
+
+impl<S> BlockingSigner<S> {
+    pub fn new(signer: S) -> Self {
+        Self(Arc::new(signer))
+    }
+}
+
+impl<S> Clone for BlockingSigner<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: ReceiptSigner + Send + Sync + 'static> AsyncReceiptSigner for BlockingSigner<S> {
+    fn sign_digest(
+        &self,
+        digest: &Bytes32,
+    ) -> impl Future<Output = Result<Signature, SignError>> + Send {
+        let (signer, digest) = (self.0.clone(), *digest);
+        offload(move || signer.sign_digest(&digest))
+    }
+
+    fn public_key(&self) -> PublicKey {
+        self.0.public_key()
+    }
+}
+
+async fn sign_async(
+    data: &[u8],
+    signer: &(impl AsyncReceiptSigner + ?Sized),
+) -> Result<Signature, SignError> {
+    canonical_signature(signer.sign_digest(&hash_bytes(data)).await?)
+}
+
+/// Run CPU heavy work on the blocking thread pool.
+async fn offload<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => result,
+        Err(err) => panic::resume_unwind(err.into_panic()),
+    }
+}
+
+/// Cancels the commit of a taken receipt unless it completes. This keeps
+/// the receipt from leaking if the commit future is dropped while waiting
+/// on the signer.
+struct PendingCommit<F: FnMut(&ReceiptId)> {
+    receipt_id: Option<ReceiptId>,
+    cancel: F,
+}
+
+impl<F: FnMut(&ReceiptId)> PendingCommit<F> {
+    fn complete(mut self) {
+        self.receipt_id = None;
+    }
+}
+
+impl<F: FnMut(&ReceiptId)> Drop for PendingCommit<F> {
+    fn drop(&mut self) {
+        if let Some(receipt_id) = self.receipt_id.take() {
+            (self.cancel)(&receipt_id);
+        }
+    }
+}
+
+async fn write_commitment_async(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signer: &(impl AsyncReceiptSigner + ?Sized),
+) -> Result<BorrowedReceipt, BorrowFail> {
+    let message = commitment_message(allocation, receipt, locked_fee);
+    let signature = sign_async(&message, signer).await?;
+    Ok(to_commitment(allocation, receipt, locked_fee, &signature))
+}
+
+impl ReceiptPool {
+    /// Like [`ReceiptPool::commit`], with an async signer.
+    pub async fn commit_async(
+        &mut self,
+        signer: &(impl AsyncReceiptSigner + ?Sized),
+        locked_fee: U256,
+    ) -> Result<BorrowedReceipt, BorrowFail> {
+        let receipt = self.take_receipt(locked_fee)?;
+        let allocation = self.allocation;
+        let pending = PendingCommit {
+            receipt_id: Some(receipt.receipt_id),
+            cancel: |receipt_id: &ReceiptId| self.cancel_commit(receipt_id),
+        };
+        let commitment = write_commitment_async(&allocation, &receipt, locked_fee, signer).await?;
+        pending.complete();
+        Ok(commitment)
+    }
+}
+
+impl ConcurrentReceiptPool {
+    /// Like [`ConcurrentReceiptPool::commit`], with an async signer. The lock
+    /// is not held while waiting on the signer.
+    pub async fn commit_async(
+        &self,
+        signer: &(impl AsyncReceiptSigner + ?Sized),
+        locked_fee: U256,
+    ) -> Result<BorrowedReceipt, BorrowFail> {
+        let receipt = self.lock().take_receipt(locked_fee)?;
+        let pending = PendingCommit {
+            receipt_id: Some(receipt.receipt_id),
+            cancel: |receipt_id: &ReceiptId| self.lock().cancel_commit(receipt_id),
+        };
+        let commitment =
+            write_commitment_async(self.allocation(), &receipt, locked_fee, signer).await?;
+        pending.complete();
+        Ok(commitment)
+    }
+}
+
+/// Like [`receipts_to_voucher`](crate::receipts_to_voucher), verifying the
+/// receipts on the blocking thread pool. Must be called from within a Tokio
+/// runtime.
+pub async fn receipts_to_voucher_async(
+    allocation_id: &Address,
+    allocation_signer: impl Into<ReceiptVerification>,
+    voucher_signer: &(impl AsyncReceiptSigner + ?Sized),
+    data: impl AsRef<[u8]> + Send + 'static,
+) -> Result<Voucher, VoucherError> {
+    let (allocation, allocation_signer) = (*allocation_id, allocation_signer.into());
+    let fees =
+        offload(move || verify_receipts(&allocation, allocation_signer, data.as_ref())).await?;
+    let message = voucher_message(allocation_id, fees);
+    Ok(Voucher {
+        allocation_id: *allocation_id,
+        fees,
+        signature: sign_async(&message, voucher_signer).await?,
+    })
+}
+
+/// Like [`receipts_to_partial_voucher`](crate::receipts_to_partial_voucher),
+/// verifying the receipts on the blocking thread pool. Must be called from
+/// within a Tokio runtime.
+pub async fn receipts_to_partial_voucher_async(
+    allocation_id: &Address,
+    allocation_signer: impl Into<ReceiptVerification>,
+    voucher_signer: &(impl AsyncReceiptSigner + ?Sized),
+    data: impl AsRef<[u8]> + Send + 'static,
+) -> Result<PartialVoucher, VoucherError> {
+    let (allocation, allocation_signer) = (*allocation_id, allocation_signer.into());
+    let (fees, (receipt_id_min, receipt_id_max)) = offload(move || {
+        let data = data.as_ref();
+        let fees = verify_receipts(&allocation, allocation_signer, data)?;
+        Ok::<_, VoucherError>((fees, receipt_id_bounds(data)?))
+    })
+    .await?;
+    let message = partial_voucher_message(allocation_id, fees, &receipt_id_min, &receipt_id_max);
+    Ok(PartialVoucher {
+        voucher: Voucher {
+            allocation_id: *allocation_id,
+            fees,
+            signature: sign_async(&message, voucher_signer).await?,
+        },
+        receipt_id_min,
+        receipt_id_max,
+    })
+}
+
+/// Like [`combine_partial_vouchers`](crate::combine_partial_vouchers),
+/// verifying the partial vouchers on the blocking thread pool. Must be
+/// called from within a Tokio runtime.
+pub async fn combine_partial_vouchers_async(
+    allocation_id: &Address,
+    voucher_signer: &(impl AsyncReceiptSigner + ?Sized),
+    partial_vouchers: impl AsRef<[PartialVoucher]> + Send + 'static,
+) -> Result<Voucher, VoucherError> {
+    combine_partial_vouchers_with_signers_async(
+        allocation_id,
+        &[ExpectedSigner::PublicKey(voucher_signer.public_key())],
+        voucher_signer,
+        partial_vouchers,
+    )
+    .await
+}
+
+/// Like [`combine_partial_vouchers_with_signers`](crate::combine_partial_vouchers_with_signers),
+/// verifying the partial vouchers on the blocking thread pool. Must be
+/// called from within a Tokio runtime.
+pub async fn combine_partial_vouchers_with_signers_async(
+    allocation_id: &Address,
+    trusted_signers: &[ExpectedSigner],
+    voucher_signer: &(impl AsyncReceiptSigner + ?Sized),
+    partial_vouchers: impl AsRef<[PartialVoucher]> + Send + 'static,
+) -> Result<Voucher, VoucherError> {
+    let (allocation, trusted_signers) = (*allocation_id, trusted_signers.to_vec());
+    let fees = offload(move || {
+        verify_partial_vouchers(&allocation, &trusted_signers, partial_vouchers.as_ref())
+    })
+    .await?;
+    let message = voucher_message(allocation_id, fees);
+    Ok(Voucher {
+        allocation_id: *allocation_id,
+        fees,
+        signature: sign_async(&message, voucher_signer).await?,
+    })
+}
+
+/// Like [`merge_partial_vouchers`](crate::merge_partial_vouchers),
+/// verifying the partial vouchers on the blocking thread pool. Must be
+/// called from within a Tokio runtime.
+pub async fn merge_partial_vouchers_async(
+    allocation_id: &Address,
+    voucher_signer: &(impl AsyncReceiptSigner + ?Sized),
+    partial_vouchers: impl AsRef<[PartialVoucher]> + Send + 'static,
+) -> Result<PartialVoucher, VoucherError> {
+    let (allocation, trusted_signers) = (
+        *allocation_id,
+        [ExpectedSigner::PublicKey(voucher_signer.public_key())],
+    );
+    let (fees, (receipt_id_min, receipt_id_max)) = offload(move || {
+        let partial_vouchers = partial_vouchers.as_ref();
+        let fees = verify_partial_vouchers(&allocation, &trusted_signers, partial_vouchers)?;
+        Ok::<_, VoucherError>((fees, partial_voucher_bounds(partial_vouchers)))
+    })
+    .await?;
+    let message = partial_voucher_message(allocation_id, fees, &receipt_id_min, &receipt_id_max);
+    Ok(PartialVoucher {
+        voucher: Voucher {
+            allocation_id: *allocation_id,
+            fees,
+            signature: sign_async(&message, voucher_signer).await?,
+        },
+        receipt_id_min,
+        receipt_id_max,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{
+        combine_partial_vouchers, merge_partial_vouchers, receipts_to_partial_voucher,
+        receipts_to_voucher, tests::*, FeeCounter, QueryStatus,
+    };
+
+    #[tokio::test]
+    async fn vouchers_match_sync() {
+        let allocation_id = bytes(1);
+        let allocation_signer = ReceiptSigner::public_key(&test_signer());
+        let receipts = create_receipts(allocation_id, 50);
+        let signer = TestSigner::default().with_delay(Duration::from_millis(1));
+
+        let voucher = receipts_to_voucher_async(
+            &allocation_id,
+            &allocation_signer,
+            &signer,
+            receipts.clone(),
+        )
+        .await
+        .unwrap();
+        let expected = receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts,
+        );
+        assert_eq!(Ok(voucher), expected);
+
+        let receipts: Arc<[u8]> = receipts.into();
+        let mut partial_vouchers = Vec::new();
+        for chunk in [0..20, 20..50] {
+            let data = receipts[chunk.start * 112..chunk.end * 112].to_vec();
+            let partial_voucher = receipts_to_partial_voucher_async(
+                &allocation_id,
+                &allocation_signer,
+                &signer,
+                data,
+            )
+            .await
+            .unwrap();
+            partial_vouchers.push(partial_voucher);
+        }
+        let expected = receipts_to_partial_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts[..20 * 112],
+        )
+        .unwrap();
+        assert_eq!(partial_vouchers[0].voucher, expected.voucher);
+
+        let combined =
+            combine_partial_vouchers_async(&allocation_id, &signer, partial_vouchers.clone())
+                .await
+                .unwrap();
+        let expected = combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers);
+        assert_eq!(Ok(combined), expected);
+
+        let merged =
+            merge_partial_vouchers_async(&allocation_id, &signer, partial_vouchers.clone())
+                .await
+                .unwrap();
+        let expected = merge_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers);
+        assert_eq!(merged.voucher, expected.unwrap().voucher);
+
+        let err = receipts_to_voucher_async(&bytes(2), &allocation_signer, &signer, receipts).await;
+        assert_eq!(err, Err(VoucherError::InvalidSignature));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_commits() {
+        let pool = Arc::new(ConcurrentReceiptPool::new(bytes(1)));
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let signer = TestSigner::default().with_delay(Duration::from_millis(5));
+                    for _ in 0..10 {
+                        let borrow = pool.commit_async(&signer, 1.into()).await.unwrap();
+                        pool.release(&borrow, QueryStatus::Success).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        let stats = pool.stats();
+        assert_eq!(stats.borrowed_receipts, 0);
+        assert_eq!(stats.unlocked_fees, U256::from(160));
+    }
+
+    #[tokio::test]
+    async fn blocking_signer_runs_off_the_runtime() {
+        let signer =
+            BlockingSigner::new(TestSigner::default().with_delay(Duration::from_millis(200)));
+        let mut pool = ReceiptPool::new(bytes(1));
+        tokio::select! {
+            biased;
+            _ = pool.commit_async(&signer, 1.into()) => panic!("Signer blocked the runtime"),
+            _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+        }
+
+        assert_eq!(pool.borrowed().count(), 0);
+
+        let borrow = pool.commit_async(&signer, 1.into()).await.unwrap();
+        let allocation_signer = ReceiptSigner::public_key(&test_signer());
+        assert!(receipts_to_voucher(
+            &bytes(1),
+            &allocation_signer,
+            &test_signer(),
+            borrow.receipt()
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn cancelled_commit_returns_receipt() {
+        let signer = BlockingSigner::new(test_signer());
+        let mut pool = ReceiptPool::new(bytes(1));
+        let borrow = pool.commit_async(&signer, 3.into()).await.unwrap();
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+
+        let slow_signer = TestSigner::default().with_delay(Duration::from_secs(60));
+        let commit = pool.commit_async(&slow_signer, 1.into());
+        assert!(tokio::time::timeout(Duration::from_millis(10), commit)
+            .await
+            .is_err());
+        assert_eq!(pool.borrowed().count(), 0);
+        let stats = pool.stats();
+        assert_eq!(stats.cached_receipts, 1);
+        assert_eq!(
+            stats.lifetime.committed,
+            FeeCounter {
+                count: 1,
+                fees: 3.into()
+            }
+        );
+
+        let borrow = pool.commit_async(&signer, 1.into()).await.unwrap();
+        assert_eq!(borrow.unlocked_fee(), U256::from(3));
+    }
+}
@@ -1,19 +1,20 @@
-use std::fmt;
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashSet, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
 
-use rand::RngCore;
-use secp256k1::SecretKey;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng as _};
 
-use crate::prelude::*;
-
-// Keep track of the offsets to index the data in an array.
-// I'm really happy with how this turned out to make book-keeping easier.
-// A macro might make this better though.
-const ALLOCATION_ID_RANGE: Range = next_range::<Address>(0..0);
-const FEE_RANGE: Range = next_range::<U256>(ALLOCATION_ID_RANGE);
-const RECEIPT_ID_RANGE: Range = next_range::<ReceiptId>(FEE_RANGE);
-const SIGNATURE_RANGE: Range = next_range::<Signature>(RECEIPT_ID_RANGE);
-const UNLOCKED_FEE_RANGE: Range = next_range::<U256>(SIGNATURE_RANGE);
-pub const BORROWED_RECEIPT_LEN: usize = UNLOCKED_FEE_RANGE.end;
+use crate::{
+    borrowed::*,
+    prelude::*,
+    receipt_id::{ReceiptIdDerivation, ReceiptIdDerivationError, ReceiptIdPrefix},
+    selection::{ReceiptSelection, UniformRandom},
+    snapshot::{BorrowSnapshot, PoolSnapshot, SnapshotError},
+    ReceiptSigner,
+};
 
 /// A per-allocation collection that can borrow or generate receipts.
 #[derive(Debug, PartialEq, Eq)]
@@ -21,9 +22,69 @@ pub struct ReceiptPool {
     pub allocation: Address,
     /// Receipts that can be folded. These contain an unbroken chain
     /// of agreed upon history between the Indexer and Gateway.
-    receipt_cache: Vec<PooledReceipt>,
+    /// Ordered from least to most recently released while the selection
+    /// strategy uses the release order, and otherwise in no particular order.
+    receipt_cache: VecDeque<PooledReceipt>,
+    /// Receipts which have been committed but not yet released, with the
+    /// state they had before the commit.
+    borrowed: BTreeMap<ReceiptId, Borrow>,
+    /// How long a receipt may be borrowed before it is eligible to be
+    /// reclaimed by [`ReceiptPool::reclaim_expired`].
+    borrow_timeout: Option<Duration>,
+    lifetime: LifetimeStats,
+    /// The maximum sum of unlocked and locked fees the pool may hold.
+    budget: Option<U256>,
+    /// Running totals of the fees reported by [`ReceiptPool::stats`], so
+    /// that the budget can be checked without visiting every receipt.
+    /// Commits which would overflow a total are rejected, so their sum
+    /// always fits in a U256.
+    unlocked_fees: U256,
+    locked_fees: U256,
+    selection: PoolSelection,
+    /// Receipts which are no longer committed, because their unlocked fee
+    /// reached `max_receipt_fee`. These still need to be collected.
+    retired: Vec<PooledReceipt>,
+    max_receipt_fee: Option<U256>,
+    /// The maximum number of receipt ids which may be cached or borrowed.
+    max_receipts: Option<usize>,
+    /// The source of randomness for minting receipt ids and for selection.
+    rng: PoolRng,
+    receipt_id_prefix: Option<ReceiptIdPrefix>,
+    receipt_id_derivation: Option<ReceiptIdDerivation>,
+    /// The counter from which the next derived receipt id is minted.
+    receipt_id_counter: u64,
+}
+
+struct PoolRng(Box<dyn RngCore + Send>);
+
+impl fmt::Debug for PoolRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PoolRng")
+    }
 }
 
+// The state of the generator can't be compared, so pools which differ only
+// in their source of randomness are equal.
+impl PartialEq for PoolRng {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for PoolRng {}
+
+#[derive(Debug)]
+struct PoolSelection(Box<dyn ReceiptSelection>);
+
+impl PartialEq for PoolSelection {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b): (&dyn Any, &dyn Any) = (&*self.0, &*other.0);
+        a.type_id() == b.type_id()
+    }
+}
+
+impl Eq for PoolSelection {}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum QueryStatus {
     Success,
@@ -32,24 +93,127 @@ pub enum QueryStatus {
 }
 
 #[derive(Eq, PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PooledReceipt {
     pub unlocked_fee: U256,
     pub receipt_id: ReceiptId,
 }
 
+/// A receipt which has been committed but not yet released.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Borrow {
+    /// The fee unlocked by the receipt before it was borrowed. This is the
+    /// value the receipt returns to if the borrow is reclaimed.
+    pub unlocked_fee: U256,
+    pub locked_fee: U256,
+    pub borrowed_at: Instant,
+}
+
+/// A summary of the receipts and fees held by a pool, for monitoring.
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct PoolStats {
+    pub cached_receipts: usize,
+    pub borrowed_receipts: usize,
+    pub retired_receipts: usize,
+    /// Fees unlocked by every receipt in the pool, including retired
+    /// receipts. For borrowed receipts this is the value they had before
+    /// they were borrowed.
+    pub unlocked_fees: U256,
+    /// Fees added to borrowed receipts, which become unlocked if the
+    /// queries they pay for succeed.
+    pub locked_fees: U256,
+    /// Counters over the lifetime of the pool. These are not persisted in
+    /// snapshots, so they restart from zero when a pool is restored.
+    pub lifetime: LifetimeStats,
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Default)]
+pub struct LifetimeStats {
+    pub committed: FeeCounter,
+    pub succeeded: FeeCounter,
+    pub failed: FeeCounter,
+    pub unknown: FeeCounter,
+}
+
+/// A number of receipts and the sum of their locked fees.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+pub struct FeeCounter {
+    pub count: u64,
+    pub fees: U256,
+}
+
+impl FeeCounter {
+    fn add(&mut self, fee: U256) {
+        self.count += 1;
+        self.fees += fee;
+    }
+
+    fn remove(&mut self, fee: U256) {
+        self.count -= 1;
+        self.fees -= fee;
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum BorrowFail {
     NoAllocation,
     InvalidRecoveryId,
+    Signer(SignError),
+    BudgetExceeded,
+    ReceiptLimitReached,
+    /// Committing the locked fee would take the fee of the receipt, or a
+    /// running total of the pool, past the largest U256.
+    FeeOverflow,
+    /// The selection strategy chose an index past the end of the receipts.
+    InvalidSelection,
 }
 
-impl std::error::Error for BorrowFail {}
+impl std::error::Error for BorrowFail {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Signer(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for BorrowFail {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::NoAllocation => write!(f, "No allocation"),
             Self::InvalidRecoveryId => SignError::InvalidRecoveryId.fmt(f),
+            Self::Signer(_) => write!(f, "Failed to sign the receipt"),
+            Self::BudgetExceeded => write!(f, "Budget exceeded"),
+            Self::ReceiptLimitReached => write!(f, "Receipt limit reached"),
+            Self::FeeOverflow => write!(f, "Fee overflow"),
+            Self::InvalidSelection => write!(f, "Selected receipt does not exist"),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ReleaseError {
+    InvalidLength,
+    WrongAllocation,
+    NotBorrowed,
+    DoubleRelease,
+    UnlockedFeeMismatch,
+    FeeMismatch,
+    NoAllocation,
+}
+
+impl std::error::Error for ReleaseError {}
+
+impl fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength => write!(f, "Invalid borrowed receipt length"),
+            Self::WrongAllocation => write!(f, "Receipt is for a different allocation"),
+            Self::NotBorrowed => write!(f, "Receipt is not borrowed from this pool"),
+            Self::DoubleRelease => write!(f, "Receipt has already been released"),
+            Self::UnlockedFeeMismatch => write!(f, "Unlocked fee does not match the borrow"),
+            Self::FeeMismatch => write!(f, "Fee does not match the borrow"),
+            Self::NoAllocation => write!(f, "No allocation"),
         }
     }
 }
@@ -58,21 +222,320 @@ impl From<SignError> for BorrowFail {
     fn from(err: SignError) -> Self {
         match err {
             SignError::InvalidRecoveryId => Self::InvalidRecoveryId,
+            err => Self::Signer(err),
         }
     }
 }
 
 impl ReceiptPool {
+    /// Create a pool which mints receipt ids from an OS-seeded CSPRNG.
     pub fn new(allocation: Address) -> Self {
+        Self::with_rng(allocation, StdRng::from_entropy())
+    }
+
+    /// Create a pool which draws randomness from the given generator. A
+    /// seeded generator makes the behavior of the pool reproducible, which
+    /// is useful for tests and simulations.
+    pub fn with_rng<R>(allocation: Address, rng: R) -> Self
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
         Self {
             allocation,
             receipt_cache: Default::default(),
+            borrowed: Default::default(),
+            borrow_timeout: None,
+            lifetime: Default::default(),
+            budget: None,
+            unlocked_fees: U256::zero(),
+            locked_fees: U256::zero(),
+            selection: PoolSelection(Box::new(UniformRandom)),
+            retired: Vec::new(),
+            max_receipt_fee: None,
+            max_receipts: None,
+            rng: PoolRng(Box::new(rng)),
+            receipt_id_prefix: None,
+            receipt_id_derivation: None,
+            receipt_id_counter: 0,
+        }
+    }
+
+    /// Mint receipt ids starting with the given prefix. Gateway replicas
+    /// signing for the same allocation should each use a distinct prefix of
+    /// the same length. Receipts already in the pool keep their ids. This
+    /// only applies to random receipt ids. Derived receipt ids use the
+    /// prefix of the derivation.
+    pub fn set_receipt_id_prefix(&mut self, prefix: Option<ReceiptIdPrefix>) {
+        self.receipt_id_prefix = prefix;
+    }
+
+    /// Mint receipt ids by derivation rather than at random. Each minted id
+    /// increments the receipt id counter, which is persisted in snapshots
+    /// so that a restored pool doesn't mint the same ids again. The
+    /// derivation must be for the allocation of the pool.
+    pub fn set_receipt_id_derivation(
+        &mut self,
+        derivation: Option<ReceiptIdDerivation>,
+    ) -> Result<(), ReceiptIdDerivationError> {
+        if let Some(derivation) = &derivation {
+            if derivation.allocation() != &self.allocation {
+                return Err(ReceiptIdDerivationError::AllocationMismatch);
+            }
+        }
+        self.receipt_id_derivation = derivation;
+        Ok(())
+    }
+
+    /// The counter from which the next derived receipt id will be minted.
+    /// Every derived receipt id minted by this pool has a lower counter.
+    pub fn receipt_id_counter(&self) -> u64 {
+        self.receipt_id_counter
+    }
+
+    pub fn set_receipt_id_counter(&mut self, counter: u64) {
+        self.receipt_id_counter = counter;
+    }
+
+    /// Replace the source of randomness, such as for a restored pool.
+    pub fn set_rng<R>(&mut self, rng: R)
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
+        self.rng = PoolRng(Box::new(rng));
+    }
+
+    /// Retire receipts once their unlocked fee reaches the given value, so
+    /// that the value riding on any one receipt id is bounded. Retired
+    /// receipts are no longer committed, and a fresh receipt id is minted
+    /// in their place.
+    pub fn set_max_receipt_fee(&mut self, max_receipt_fee: Option<U256>) {
+        self.max_receipt_fee = max_receipt_fee;
+        for receipt in std::mem::take(&mut self.receipt_cache) {
+            self.cache_receipt(receipt);
+        }
+    }
+
+    /// Limit the number of receipt ids which are cached or borrowed. Once the
+    /// limit is reached and every receipt is borrowed, commits fail with
+    /// [`BorrowFail::ReceiptLimitReached`] instead of minting a new receipt
+    /// id. Retired receipts don't count towards the limit.
+    pub fn set_max_receipts(&mut self, max_receipts: Option<usize>) {
+        self.max_receipts = max_receipts;
+    }
+
+    /// Receipts which have been retired, and should be collected.
+    pub fn retired_receipts(&self) -> &[PooledReceipt] {
+        &self.retired
+    }
+
+    /// Remove the retired receipts from the pool, such as after they have
+    /// been folded into a voucher.
+    pub fn take_retired_receipts(&mut self) -> Vec<PooledReceipt> {
+        let retired = std::mem::take(&mut self.retired);
+        for receipt in &retired {
+            self.unlocked_fees -= receipt.unlocked_fee;
+        }
+        retired
+    }
+
+    fn cache_receipt(&mut self, receipt: PooledReceipt) {
+        match self.max_receipt_fee {
+            Some(max) if receipt.unlocked_fee >= max => self.retired.push(receipt),
+            _ => self.receipt_cache.push_back(receipt),
+        }
+    }
+
+    fn is_released(&self, receipt_id: &ReceiptId) -> bool {
+        self.receipt_cache
+            .iter()
+            .chain(&self.retired)
+            .any(|r| &r.receipt_id == receipt_id)
+    }
+
+    /// Set the strategy used to choose which cached receipt to commit.
+    /// Receipts released while a strategy which doesn't use the release
+    /// order is set are cached in no particular order.
+    pub fn set_selection(&mut self, selection: impl ReceiptSelection + 'static) {
+        self.selection = PoolSelection(Box::new(selection));
+    }
+
+    /// Limit the fees the pool may expose, counting both unlocked fees and
+    /// the fees locked by borrowed receipts. Commits which would exceed the
+    /// budget fail with [`BorrowFail::BudgetExceeded`]. Lowering the budget
+    /// below the current exposure does not affect existing receipts.
+    pub fn set_budget(&mut self, budget: Option<U256>) {
+        self.budget = budget;
+    }
+
+    pub fn budget(&self) -> Option<U256> {
+        self.budget
+    }
+
+    /// The sum of unlocked and locked fees held by the pool.
+    pub fn exposure(&self) -> U256 {
+        self.unlocked_fees + self.locked_fees
+    }
+
+    /// Whether the locked fee can be committed without overflowing a running
+    /// total. The fee is added to the exposure and to the committed fees
+    /// now, and to one of the released fee counters when it is released.
+    fn has_room_for(&self, locked_fee: U256) -> bool {
+        let lifetime = &self.lifetime;
+        let released = [&lifetime.succeeded, &lifetime.failed, &lifetime.unknown]
+            .into_iter()
+            .map(|counter| counter.fees)
+            .max()
+            .unwrap();
+        [
+            self.exposure(),
+            lifetime.committed.fees,
+            released + self.locked_fees,
+        ]
+        .into_iter()
+        .all(|total| total.checked_add(locked_fee).is_some())
+    }
+
+    /// The largest fee that could be committed without exceeding the
+    /// budget, or `None` if the pool has no budget.
+    pub fn headroom(&self) -> Option<U256> {
+        self.budget
+            .map(|budget| budget.saturating_sub(self.exposure()))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            cached_receipts: self.receipt_cache.len(),
+            borrowed_receipts: self.borrowed.len(),
+            retired_receipts: self.retired.len(),
+            unlocked_fees: self.unlocked_fees,
+            locked_fees: self.locked_fees,
+            lifetime: self.lifetime.clone(),
         }
     }
 
-    /// This is only a minimum bound, and doesn't count
-    /// outstanding/forgotten receipts which may have account for a
-    /// significant portion of unlocked fees
+    pub fn set_borrow_timeout(&mut self, timeout: Option<Duration>) {
+        self.borrow_timeout = timeout;
+    }
+
+    /// Receipts which have been committed but not yet released.
+    pub fn borrowed(&self) -> impl Iterator<Item = (&ReceiptId, &Borrow)> {
+        self.borrowed.iter()
+    }
+
+    /// Return receipts which have been borrowed for longer than the borrow
+    /// timeout to the pool at the value they had before they were committed.
+    /// This recovers the unlocked fees of receipts whose borrowed bytes were
+    /// lost by the caller. Returns the ids of the reclaimed receipts.
+    pub fn reclaim_expired(&mut self) -> Vec<ReceiptId> {
+        let timeout = match self.borrow_timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        let now = Instant::now();
+        let expired: Vec<ReceiptId> = self
+            .borrowed
+            .iter()
+            .filter(|(_, borrow)| now.saturating_duration_since(borrow.borrowed_at) >= timeout)
+            .map(|(receipt_id, _)| *receipt_id)
+            .collect();
+        for receipt_id in &expired {
+            self.return_borrow(receipt_id);
+        }
+        expired
+    }
+
+    /// Capture the state of the pool, including receipts which are currently
+    /// borrowed, so that it can be persisted and restored later.
+    pub fn snapshot(&self) -> PoolSnapshot {
+        PoolSnapshot {
+            allocation: self.allocation,
+            receipts: self.receipt_cache.iter().cloned().collect(),
+            retired: self.retired.clone(),
+            receipt_id_counter: self.receipt_id_counter,
+            borrowed: self
+                .borrowed
+                .iter()
+                .map(|(receipt_id, borrow)| BorrowSnapshot {
+                    receipt_id: *receipt_id,
+                    unlocked_fee: borrow.unlocked_fee,
+                    locked_fee: borrow.locked_fee,
+                })
+                .collect(),
+        }
+    }
+
+    /// Rebuild a pool from a snapshot taken with [`ReceiptPool::snapshot`].
+    /// The snapshot must be for the given allocation, and may not mention
+    /// the same receipt id twice. Borrowed receipts are treated as if they
+    /// were borrowed at the time of the restore.
+    ///
+    /// Only the receipts and the receipt id counter are restored. The pool is
+    /// otherwise configured as by [`ReceiptPool::new`], so the budget,
+    /// selection strategy, max receipt fee, max receipts, borrow timeout,
+    /// receipt id prefix and receipt id derivation must be set again before
+    /// committing. Until the derivation is set, receipt ids are minted at
+    /// random.
+    pub fn restore(allocation: Address, snapshot: PoolSnapshot) -> Result<Self, SnapshotError> {
+        if snapshot.allocation != allocation {
+            return Err(SnapshotError::AllocationMismatch);
+        }
+        let mut ids = HashSet::new();
+        let unique = snapshot
+            .receipts
+            .iter()
+            .chain(&snapshot.retired)
+            .map(|r| &r.receipt_id)
+            .chain(snapshot.borrowed.iter().map(|b| &b.receipt_id))
+            .all(|id| ids.insert(id));
+        if !unique {
+            return Err(SnapshotError::DuplicateReceiptId);
+        }
+
+        let mut pool = Self::new(allocation);
+        let now = Instant::now();
+        pool.borrowed = snapshot
+            .borrowed
+            .into_iter()
+            .map(|borrow| {
+                let entry = Borrow {
+                    unlocked_fee: borrow.unlocked_fee,
+                    locked_fee: borrow.locked_fee,
+                    borrowed_at: now,
+                };
+                (borrow.receipt_id, entry)
+            })
+            .collect();
+        pool.receipt_cache = snapshot.receipts.into();
+        pool.retired = snapshot.retired;
+        let unlocked_fees = pool
+            .receipt_cache
+            .iter()
+            .chain(&pool.retired)
+            .map(|receipt| receipt.unlocked_fee)
+            .chain(pool.borrowed.values().map(|borrow| borrow.unlocked_fee))
+            .try_fold(U256::zero(), |sum, fee| sum.checked_add(fee));
+        let locked_fees = pool
+            .borrowed
+            .values()
+            .try_fold(U256::zero(), |sum, borrow| {
+                sum.checked_add(borrow.locked_fee)
+            });
+        match (unlocked_fees, locked_fees) {
+            (Some(unlocked_fees), Some(locked_fees))
+                if unlocked_fees.checked_add(locked_fees).is_some() =>
+            {
+                pool.unlocked_fees = unlocked_fees;
+                pool.locked_fees = locked_fees;
+            }
+            _ => return Err(SnapshotError::FeeOverflow),
+        }
+        pool.receipt_id_counter = snapshot.receipt_id_counter;
+        Ok(pool)
+    }
+
+    /// This is only a minimum bound, and doesn't count borrowed
+    /// receipts which may account for a significant portion of
+    /// unlocked fees until they are released or reclaimed.
     #[cfg(test)]
     pub fn known_unlocked_fees(&self) -> U256 {
         let mut result = U256::zero();
@@ -82,78 +545,243 @@ impl ReceiptPool {
         result
     }
 
-    pub fn commit(&mut self, signer: &SecretKey, locked_fee: U256) -> Result<Vec<u8>, BorrowFail> {
+    pub fn commit(
+        &mut self,
+        signer: &(impl ReceiptSigner + ?Sized),
+        locked_fee: U256,
+    ) -> Result<BorrowedReceipt, BorrowFail> {
+        let receipt = self.take_receipt(locked_fee)?;
+        write_commitment(&self.allocation, &receipt, locked_fee, signer).inspect_err(|_| {
+            self.cancel_commit(&receipt.receipt_id);
+        })
+    }
+
+    /// Pick a receipt to commit and record it as borrowed. This is the only
+    /// part of a commit which requires the mutable borrow. The remainder is
+    /// in `write_commitment` so that signing can happen outside of any lock.
+    pub(crate) fn take_receipt(&mut self, locked_fee: U256) -> Result<PooledReceipt, BorrowFail> {
+        if let Some(headroom) = self.headroom() {
+            if locked_fee > headroom {
+                return Err(BorrowFail::BudgetExceeded);
+            }
+        }
+        if !self.has_room_for(locked_fee) {
+            return Err(BorrowFail::FeeOverflow);
+        }
+
         let receipt = if self.receipt_cache.is_empty() {
-            let mut receipt_id = ReceiptId::default();
-            rng().fill_bytes(&mut receipt_id);
+            if let Some(max_receipts) = self.max_receipts {
+                if self.borrowed.len() >= max_receipts {
+                    return Err(BorrowFail::ReceiptLimitReached);
+                }
+            }
             PooledReceipt {
-                receipt_id,
+                receipt_id: self.mint_receipt_id(),
                 unlocked_fee: U256::zero(),
             }
         } else {
-            let receipts = &mut self.receipt_cache;
-            let index = rng().gen_range(0..receipts.len());
-            receipts.swap_remove(index)
+            let receipts = self.receipt_cache.make_contiguous();
+            let index = self.selection.0.select(receipts, &mut *self.rng.0);
+            let selected = receipts.get(index).ok_or(BorrowFail::InvalidSelection)?;
+            // Checked before the receipt is borrowed, so that the fee of a
+            // commitment can't overflow.
+            if selected.unlocked_fee.checked_add(locked_fee).is_none() {
+                return Err(BorrowFail::FeeOverflow);
+            }
+            let receipt = if self.selection.0.uses_release_order() {
+                self.receipt_cache.remove(index)
+            } else {
+                self.receipt_cache.swap_remove_back(index)
+            };
+            receipt.unwrap()
         };
 
-        // Technically we don't need the mutable borrow from here on out.
-        // If we ever need to unlock more concurency when these are locked
-        // it would be possible to split out the remainder of this method.
-
-        // Write the data in the official receipt that gets sent over the wire.
-        // This is: [allocation_id, fee, receipt_id, signature]
-        let mut commitment = Vec::with_capacity(BORROWED_RECEIPT_LEN);
-        let fee = receipt.unlocked_fee + locked_fee;
-        commitment.extend_from_slice(&self.allocation);
-        commitment.extend_from_slice(&to_be_bytes(fee));
-        commitment.extend_from_slice(&receipt.receipt_id);
-
-        // Engineering in any kind of replay protection like as afforded by EIP-712 is
-        // unnecessary, because the signer key needs to be unique per app. It is a straightforward
-        // extension from there to also say that the signer key should be globally unique and
-        // not sign any messages that are not for the app. Since there are no other structs
-        // to sign, there are no possible collisions.
-        //
-        // The part of the message that needs to be signed in the fee and receipt id only.
-        let signature = sign(
-            &commitment[ALLOCATION_ID_RANGE.start..RECEIPT_ID_RANGE.end],
-            signer,
-        )?;
-        commitment.extend_from_slice(&signature);
-
-        // Extend with the unlocked fee, which is necessary to return collateral
-        // in the case of failure.
-        commitment.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
-
-        debug_assert_eq!(BORROWED_RECEIPT_LEN, commitment.len());
-
-        Ok(commitment)
-    }
-
-    pub fn release(&mut self, bytes: &[u8], status: QueryStatus) {
-        assert_eq!(bytes.len(), BORROWED_RECEIPT_LEN);
-
-        let unlocked_fee = if status == QueryStatus::Success {
-            U256::from_big_endian(&bytes[FEE_RANGE])
-        } else {
-            U256::from_big_endian(&bytes[UNLOCKED_FEE_RANGE])
+        self.borrowed.insert(
+            receipt.receipt_id,
+            Borrow {
+                unlocked_fee: receipt.unlocked_fee,
+                locked_fee,
+                borrowed_at: Instant::now(),
+            },
+        );
+        self.locked_fees += locked_fee;
+        self.lifetime.committed.add(locked_fee);
+
+        Ok(receipt)
+    }
+
+    fn mint_receipt_id(&mut self) -> ReceiptId {
+        if let Some(derivation) = &self.receipt_id_derivation {
+            let receipt_id = derivation.derive(self.receipt_id_counter);
+            self.receipt_id_counter += 1;
+            return receipt_id;
+        }
+        let mut receipt_id = ReceiptId::default();
+        self.rng.0.fill_bytes(&mut receipt_id);
+        if let Some(prefix) = &self.receipt_id_prefix {
+            prefix.apply(&mut receipt_id);
+        }
+        receipt_id
+    }
+
+    /// Undo `take_receipt` for a commit which didn't complete, such as when
+    /// signing fails. The receipt was never handed out, so it isn't counted
+    /// as committed.
+    pub(crate) fn cancel_commit(&mut self, receipt_id: &ReceiptId) {
+        if let Some(borrow) = self.borrowed.get(receipt_id) {
+            self.lifetime.committed.remove(borrow.locked_fee);
+            self.return_borrow(receipt_id);
+        }
+    }
+
+    /// Put a borrowed receipt back into the cache at the value it had before
+    /// it was borrowed.
+    pub(crate) fn return_borrow(&mut self, receipt_id: &ReceiptId) {
+        if let Some(borrow) = self.borrowed.remove(receipt_id) {
+            self.locked_fees -= borrow.locked_fee;
+            self.cache_receipt(PooledReceipt {
+                unlocked_fee: borrow.unlocked_fee,
+                receipt_id: *receipt_id,
+            });
+        }
+    }
+
+    /// Return a borrowed receipt to the pool. On success the receipt
+    /// unlocks the full fee it was committed with, otherwise it returns to
+    /// the unlocked fee it had before it was borrowed.
+    pub fn release(
+        &mut self,
+        borrowed: impl AsRef<[u8]>,
+        status: QueryStatus,
+    ) -> Result<(), ReleaseError> {
+        let borrowed = BorrowedReceipt::try_from(borrowed.as_ref())
+            .map_err(|_| ReleaseError::InvalidLength)?;
+        if borrowed.allocation_id() != &self.allocation {
+            return Err(ReleaseError::WrongAllocation);
+        }
+
+        let receipt_id = *borrowed.receipt_id();
+        let borrow = match self.borrowed.get(&receipt_id) {
+            Some(borrow) => borrow,
+            None if self.is_released(&receipt_id) => return Err(ReleaseError::DoubleRelease),
+            None => return Err(ReleaseError::NotBorrowed),
+        };
+
+        let fee = borrowed.fee();
+        let unlocked_fee = borrowed.unlocked_fee();
+        // The unlocked fee of a receipt never decreases, so a commitment from
+        // before the receipt was borrowed again has a lower unlocked fee, or
+        // a fee which has since been unlocked. A commitment with no locked
+        // fee unlocks nothing, so its fee is the unlocked fee. A stale
+        // commitment with the same unlocked fee can't be told apart from a
+        // wrong fee.
+        let unlocked_since = !borrow.locked_fee.is_zero() && fee <= borrow.unlocked_fee;
+        if unlocked_fee < borrow.unlocked_fee || unlocked_since {
+            return Err(ReleaseError::DoubleRelease);
+        }
+        if unlocked_fee != borrow.unlocked_fee {
+            return Err(ReleaseError::UnlockedFeeMismatch);
+        }
+        if borrow.unlocked_fee.checked_add(borrow.locked_fee) != Some(fee) {
+            return Err(ReleaseError::FeeMismatch);
+        }
+
+        let counter = match status {
+            QueryStatus::Success => &mut self.lifetime.succeeded,
+            QueryStatus::Failure => &mut self.lifetime.failed,
+            QueryStatus::Unknown => &mut self.lifetime.unknown,
         };
+        counter.add(borrow.locked_fee);
 
+        self.locked_fees -= borrow.locked_fee;
+        if status == QueryStatus::Success {
+            self.unlocked_fees += borrow.locked_fee;
+        }
+        self.borrowed.remove(&receipt_id);
         let receipt = PooledReceipt {
-            unlocked_fee,
-            receipt_id: bytes[RECEIPT_ID_RANGE].try_into().unwrap(),
+            unlocked_fee: if status == QueryStatus::Success {
+                fee
+            } else {
+                unlocked_fee
+            },
+            receipt_id,
         };
-        self.receipt_cache.push(receipt);
+        self.cache_receipt(receipt);
+        Ok(())
     }
 }
 
+pub(crate) fn write_commitment(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signer: &(impl ReceiptSigner + ?Sized),
+) -> Result<BorrowedReceipt, BorrowFail> {
+    let message = commitment_message(allocation, receipt, locked_fee);
+    let signature = sign(&message, signer)?;
+    Ok(to_commitment(allocation, receipt, locked_fee, &signature))
+}
+
+/// The message signed to commit the receipt with the locked fee.
+pub(crate) fn commitment_message(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+) -> Vec<u8> {
+    let fee = committed_fee(receipt, locked_fee);
+
+    // Engineering in any kind of replay protection like as afforded by EIP-712 is
+    // unnecessary, because the signer key needs to be unique per app. It is a straightforward
+    // extension from there to also say that the signer key should be globally unique and
+    // not sign any messages that are not for the app. Since there are no other structs
+    // to sign, there are no possible collisions.
+    //
+    // The part of the message that needs to be signed in the fee and receipt id only.
+    let mut message = Vec::with_capacity(RECEIPT_ID_RANGE.end);
+    message.extend_from_slice(allocation);
+    message.extend_from_slice(&to_be_bytes(fee));
+    message.extend_from_slice(&receipt.receipt_id);
+    message
+}
+
+// The receipt was taken by `take_receipt`, which rejects a locked fee that
+// would overflow.
+fn committed_fee(receipt: &PooledReceipt, locked_fee: U256) -> U256 {
+    receipt
+        .unlocked_fee
+        .checked_add(locked_fee)
+        .expect("Fee overflow is checked when the receipt is taken")
+}
+
+pub(crate) fn to_commitment(
+    allocation: &Address,
+    receipt: &PooledReceipt,
+    locked_fee: U256,
+    signature: &Signature,
+) -> BorrowedReceipt {
+    // The unlocked fee is included, which is necessary to return collateral
+    // in the case of failure.
+    let commitment = BorrowedReceipt::new(
+        allocation,
+        committed_fee(receipt, locked_fee),
+        &receipt.receipt_id,
+        signature,
+        receipt.unlocked_fee,
+    );
+    debug_assert_eq!(
+        commitment.signed_message(),
+        &commitment_message(allocation, receipt, locked_fee)[..]
+    );
+    commitment
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::tests::*;
 
     #[track_caller]
-    fn assert_successful_borrow(pool: &mut ReceiptPool, fee: impl Into<U256>) -> Vec<u8> {
+    fn assert_successful_borrow(pool: &mut ReceiptPool, fee: impl Into<U256>) -> BorrowedReceipt {
         pool.commit(&test_signer(), fee.into())
             .expect("Should be able to borrow")
     }
@@ -165,7 +793,7 @@ mod tests {
 
         for i in 1..=10 {
             let borrow = assert_successful_borrow(&mut pool, i);
-            pool.release(&borrow, QueryStatus::Success);
+            pool.release(&borrow, QueryStatus::Success).unwrap();
             // Verify that we have unlocked all the fees
             let unlocked: u32 = (0..=i).sum();
             assert_eq!(U256::from(unlocked), pool.known_unlocked_fees());
@@ -183,16 +811,359 @@ mod tests {
         let borrow2 = assert_successful_borrow(&mut pool, 2);
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
-        pool.release(&borrow3, QueryStatus::Failure);
+        pool.release(&borrow3, QueryStatus::Failure).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
         let borrow4 = assert_successful_borrow(&mut pool, 4);
         assert_eq!(pool.known_unlocked_fees(), 0.into());
 
-        pool.release(&borrow2, QueryStatus::Success);
+        pool.release(&borrow2, QueryStatus::Success).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 2.into());
 
-        pool.release(&borrow4, QueryStatus::Unknown);
+        pool.release(&borrow4, QueryStatus::Unknown).unwrap();
         assert_eq!(pool.known_unlocked_fees(), 2.into());
     }
+
+    #[test]
+    fn stats() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        assert_eq!(pool.stats(), PoolStats::default());
+
+        let borrow1 = assert_successful_borrow(&mut pool, 1);
+        let borrow2 = assert_successful_borrow(&mut pool, 2);
+        let borrow3 = assert_successful_borrow(&mut pool, 3);
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        pool.release(&borrow2, QueryStatus::Failure).unwrap();
+
+        let stats = pool.stats();
+        assert_eq!(stats.cached_receipts, 2);
+        assert_eq!(stats.borrowed_receipts, 1);
+        assert_eq!(stats.unlocked_fees, 1.into());
+        assert_eq!(stats.locked_fees, 3.into());
+        assert_eq!(
+            stats.lifetime.committed,
+            FeeCounter {
+                count: 3,
+                fees: 6.into()
+            }
+        );
+        assert_eq!(
+            stats.lifetime.succeeded,
+            FeeCounter {
+                count: 1,
+                fees: 1.into()
+            }
+        );
+        assert_eq!(
+            stats.lifetime.failed,
+            FeeCounter {
+                count: 1,
+                fees: 2.into()
+            }
+        );
+        assert_eq!(stats.lifetime.unknown, FeeCounter::default());
+
+        pool.release(&borrow3, QueryStatus::Unknown).unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.borrowed_receipts, 0);
+        assert_eq!(stats.locked_fees, 0.into());
+        assert_eq!(
+            stats.lifetime.unknown,
+            FeeCounter {
+                count: 1,
+                fees: 3.into()
+            }
+        );
+    }
+
+    #[test]
+    fn budget() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        assert_eq!(pool.headroom(), None);
+        pool.set_budget(Some(10.into()));
+
+        let borrow1 = assert_successful_borrow(&mut pool, 4);
+        let borrow2 = assert_successful_borrow(&mut pool, 5);
+        assert_eq!(pool.exposure(), 9.into());
+        assert_eq!(pool.headroom(), Some(1.into()));
+        assert_eq!(
+            pool.commit(&test_signer(), 2.into()),
+            Err(BorrowFail::BudgetExceeded)
+        );
+
+        // Failed queries return their locked fees to the budget.
+        pool.release(&borrow2, QueryStatus::Failure).unwrap();
+        assert_eq!(pool.headroom(), Some(6.into()));
+        let borrow3 = assert_successful_borrow(&mut pool, 6);
+        assert_eq!(pool.headroom(), Some(0.into()));
+
+        // Successful queries keep their fees in the exposure.
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        pool.release(&borrow3, QueryStatus::Success).unwrap();
+        assert_eq!(pool.exposure(), 10.into());
+
+        pool.set_budget(Some(5.into()));
+        assert_eq!(pool.headroom(), Some(0.into()));
+        assert_eq!(
+            pool.commit(&test_signer(), 1.into()),
+            Err(BorrowFail::BudgetExceeded)
+        );
+        pool.set_budget(Some(11.into()));
+        assert_successful_borrow(&mut pool, 1);
+    }
+
+    #[test]
+    fn fee_overflow() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        let borrow = pool.commit(&test_signer(), U256::MAX - 1).unwrap();
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+
+        assert_eq!(
+            pool.commit(&test_signer(), 2.into()),
+            Err(BorrowFail::FeeOverflow)
+        );
+        // The receipt stays in the cache and can take the remaining fee.
+        let stats = pool.stats();
+        assert_eq!((stats.cached_receipts, stats.borrowed_receipts), (1, 0));
+        assert_eq!(stats.lifetime.committed.count, 1);
+        let borrow = pool.commit(&test_signer(), 1.into()).unwrap();
+        assert_eq!(borrow.fee(), U256::MAX);
+        assert_eq!(borrow.unlocked_fee(), U256::MAX - 1);
+
+        // The running totals of receipts near the largest U256 stay exact.
+        let mut pool = ReceiptPool::new(bytes(2));
+        let half = U256::MAX / 2;
+        let borrow1 = assert_successful_borrow(&mut pool, half);
+        let borrow2 = assert_successful_borrow(&mut pool, half);
+        assert_eq!(
+            pool.commit(&test_signer(), 2.into()),
+            Err(BorrowFail::FeeOverflow)
+        );
+        assert_eq!(pool.exposure(), U256::MAX - 1);
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        pool.release(&borrow2, QueryStatus::Failure).unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.unlocked_fees, half);
+        assert_eq!(stats.locked_fees, 0.into());
+        assert_eq!(pool.headroom(), None);
+
+        // The committed fees fill their counter, so no more can be committed.
+        let borrow3 = assert_successful_borrow(&mut pool, 1);
+        assert_eq!(pool.stats().lifetime.committed.fees, U256::MAX);
+        assert_eq!(
+            pool.commit(&test_signer(), 1.into()),
+            Err(BorrowFail::FeeOverflow)
+        );
+        pool.release(&borrow3, QueryStatus::Failure).unwrap();
+        assert_eq!(pool.exposure(), half);
+        assert_eq!(pool.stats().locked_fees, 0.into());
+    }
+
+    #[test]
+    fn retires_receipts_at_max_fee() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        pool.set_max_receipt_fee(Some(5.into()));
+
+        let borrow1 = assert_successful_borrow(&mut pool, 3);
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        let borrow2 = assert_successful_borrow(&mut pool, 2);
+        assert_eq!(borrow1.receipt_id(), borrow2.receipt_id());
+        pool.release(&borrow2, QueryStatus::Success).unwrap();
+        assert_eq!(
+            pool.retired_receipts(),
+            &[PooledReceipt {
+                unlocked_fee: 5.into(),
+                receipt_id: *borrow1.receipt_id(),
+            }]
+        );
+        assert_eq!(
+            pool.release(&borrow2, QueryStatus::Success),
+            Err(ReleaseError::DoubleRelease)
+        );
+
+        // A fresh receipt id is minted in place of the retired receipt.
+        let borrow3 = assert_successful_borrow(&mut pool, 1);
+        assert_ne!(borrow3.receipt_id(), borrow1.receipt_id());
+        assert_eq!(borrow3.unlocked_fee(), 0.into());
+        pool.release(&borrow3, QueryStatus::Success).unwrap();
+        assert_eq!(pool.stats().unlocked_fees, 6.into());
+
+        // Lowering the limit retires cached receipts.
+        pool.set_max_receipt_fee(Some(1.into()));
+        assert_eq!(pool.stats().cached_receipts, 0);
+        assert_eq!(pool.take_retired_receipts().len(), 2);
+        assert_eq!(pool.exposure(), 0.into());
+        assert!(pool.retired_receipts().is_empty());
+    }
+
+    #[test]
+    fn limits_receipt_count() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        pool.set_max_receipts(Some(2));
+
+        let borrow1 = assert_successful_borrow(&mut pool, 1);
+        let _borrow2 = assert_successful_borrow(&mut pool, 1);
+        assert_eq!(
+            pool.commit(&test_signer(), 1.into()),
+            Err(BorrowFail::ReceiptLimitReached)
+        );
+
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        let borrow3 = assert_successful_borrow(&mut pool, 1);
+        assert_eq!(borrow1.receipt_id(), borrow3.receipt_id());
+    }
+
+    #[test]
+    fn seeded_rng_is_deterministic() {
+        let run = |seed: u64| {
+            let mut pool = ReceiptPool::with_rng(bytes(2), StdRng::seed_from_u64(seed));
+            let mut ids = Vec::new();
+            for i in 0..20 {
+                let borrows: Vec<_> = (0..=(i % 4))
+                    .map(|_| assert_successful_borrow(&mut pool, 1))
+                    .collect();
+                for borrow in borrows {
+                    ids.push(*borrow.receipt_id());
+                    pool.release(&borrow, QueryStatus::Success).unwrap();
+                }
+            }
+            ids
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn tracks_borrowed_receipts() {
+        let mut pool = ReceiptPool::new(bytes(2));
+
+        let borrow1 = assert_successful_borrow(&mut pool, 1);
+        let borrow2 = assert_successful_borrow(&mut pool, 2);
+        let mut locked: Vec<U256> = pool.borrowed().map(|(_, b)| b.locked_fee).collect();
+        locked.sort();
+        assert_eq!(locked, vec![1.into(), 2.into()]);
+
+        pool.release(&borrow1, QueryStatus::Success).unwrap();
+        let borrowed: Vec<(ReceiptId, Borrow)> =
+            pool.borrowed().map(|(id, b)| (*id, b.clone())).collect();
+        assert_eq!(borrowed.len(), 1);
+        assert_eq!(&borrowed[0].0, borrow2.receipt_id());
+        assert_eq!(borrowed[0].1.unlocked_fee, 0.into());
+        assert_eq!(borrowed[0].1.locked_fee, 2.into());
+    }
+
+    #[test]
+    fn reclaims_expired_borrows() {
+        let mut pool = ReceiptPool::new(bytes(2));
+
+        let borrow = assert_successful_borrow(&mut pool, 3);
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        let _lost = assert_successful_borrow(&mut pool, 4);
+        assert_eq!(pool.known_unlocked_fees(), 0.into());
+
+        // Nothing is reclaimed without a timeout, or before it expires.
+        assert!(pool.reclaim_expired().is_empty());
+        pool.set_borrow_timeout(Some(Duration::from_secs(3600)));
+        assert!(pool.reclaim_expired().is_empty());
+
+        // The receipt returns at the value it had before it was borrowed.
+        pool.set_borrow_timeout(Some(Duration::ZERO));
+        let reclaimed = pool.reclaim_expired();
+        assert_eq!(reclaimed.len(), 1);
+        assert_eq!(&reclaimed[0], borrow.receipt_id());
+        assert_eq!(pool.borrowed().count(), 0);
+        assert_eq!(pool.known_unlocked_fees(), 3.into());
+    }
+
+    #[test]
+    fn release_rejects_stale_borrows() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        let first = assert_successful_borrow(&mut pool, 5);
+        pool.release(&first, QueryStatus::Success).unwrap();
+        let failed = assert_successful_borrow(&mut pool, 2);
+        assert_eq!(failed.receipt_id(), first.receipt_id());
+        pool.release(&failed, QueryStatus::Failure).unwrap();
+
+        // The receipt id is borrowed again, but not by these commitments.
+        let current = assert_successful_borrow(&mut pool, 3);
+        assert_eq!(current.receipt_id(), first.receipt_id());
+        assert_eq!(
+            pool.release(&first, QueryStatus::Success),
+            Err(ReleaseError::DoubleRelease)
+        );
+        assert_eq!(
+            pool.release(&failed, QueryStatus::Success),
+            Err(ReleaseError::FeeMismatch)
+        );
+        pool.release(&current, QueryStatus::Success).unwrap();
+        assert_eq!(pool.known_unlocked_fees(), 8.into());
+    }
+
+    #[test]
+    fn releases_borrows_without_locked_fees() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        let free = assert_successful_borrow(&mut pool, 0);
+        pool.release(&free, QueryStatus::Success).unwrap();
+        let paid = assert_successful_borrow(&mut pool, 3);
+        assert_eq!(paid.receipt_id(), free.receipt_id());
+        pool.release(&paid, QueryStatus::Success).unwrap();
+
+        let free = assert_successful_borrow(&mut pool, 0);
+        assert_eq!(free.unlocked_fee(), 3.into());
+        pool.release(&free, QueryStatus::Success).unwrap();
+        assert_eq!(pool.borrowed().count(), 0);
+        assert_eq!(pool.known_unlocked_fees(), 3.into());
+        assert_eq!(
+            pool.release(&paid, QueryStatus::Success),
+            Err(ReleaseError::DoubleRelease)
+        );
+    }
+
+    #[test]
+    fn release_rejects_invalid_borrows() {
+        let mut pool = ReceiptPool::new(bytes(2));
+        let borrow = assert_successful_borrow(&mut pool, 5);
+
+        assert_eq!(
+            pool.release(&borrow.as_bytes()[1..], QueryStatus::Success),
+            Err(ReleaseError::InvalidLength)
+        );
+
+        let mut foreign = borrow.as_bytes().to_vec();
+        foreign[ALLOCATION_ID_RANGE].copy_from_slice(&bytes::<20>(3));
+        assert_eq!(
+            pool.release(&foreign, QueryStatus::Success),
+            Err(ReleaseError::WrongAllocation)
+        );
+
+        let mut unknown = borrow.as_bytes().to_vec();
+        unknown[RECEIPT_ID_RANGE].copy_from_slice(&bytes::<15>(4));
+        assert_eq!(
+            pool.release(&unknown, QueryStatus::Success),
+            Err(ReleaseError::NotBorrowed)
+        );
+
+        let mut inflated = borrow.as_bytes().to_vec();
+        inflated[UNLOCKED_FEE_RANGE].copy_from_slice(&to_be_bytes(1.into()));
+        assert_eq!(
+            pool.release(&inflated, QueryStatus::Failure),
+            Err(ReleaseError::UnlockedFeeMismatch)
+        );
+
+        let mut inflated = borrow.as_bytes().to_vec();
+        inflated[FEE_RANGE].copy_from_slice(&to_be_bytes(6.into()));
+        assert_eq!(
+            pool.release(&inflated, QueryStatus::Success),
+            Err(ReleaseError::FeeMismatch)
+        );
+
+        // None of the rejected releases disturbed the borrow.
+        assert_eq!(pool.borrowed().count(), 1);
+        pool.release(&borrow, QueryStatus::Success).unwrap();
+        assert_eq!(
+            pool.release(&borrow, QueryStatus::Success),
+            Err(ReleaseError::DoubleRelease)
+        );
+        assert_eq!(pool.known_unlocked_fees(), 5.into());
+    }
 }
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+
+use crate::{
+    prelude::*, BorrowFail, BorrowedReceipt, QueryStatus, ReceiptPool, ReceiptSigner, ReleaseError,
+};
+
+/// A collection of receipt pools keyed by allocation.
+///
+/// The lifecycle of an allocation is: added, then closed once no more
+/// receipts should be committed for it, then removed once the remaining
+/// borrowed receipts have been released (or abandoned). A closed
+/// allocation still accepts releases so that in-flight queries can settle.
+#[derive(Debug, Default)]
+pub struct ReceiptPools {
+    pools: HashMap<Address, AllocationPool>,
+}
+
+#[derive(Debug)]
+struct AllocationPool {
+    pool: ReceiptPool,
+    closed: bool,
+}
+
+impl ReceiptPools {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start committing receipts for the allocation. Re-opens the
+    /// allocation if it was closed. Returns false if the allocation was
+    /// already open.
+    pub fn add_allocation(&mut self, allocation: Address) -> bool {
+        match self.pools.get_mut(&allocation) {
+            Some(entry) => std::mem::replace(&mut entry.closed, false),
+            None => {
+                self.insert_pool(ReceiptPool::new(allocation));
+                true
+            }
+        }
+    }
+
+    /// Add an existing pool, such as one restored from a snapshot, replacing
+    /// any pool for the same allocation.
+    pub fn insert_pool(&mut self, pool: ReceiptPool) -> Option<ReceiptPool> {
+        let entry = AllocationPool {
+            pool,
+            closed: false,
+        };
+        self.pools
+            .insert(entry.pool.allocation, entry)
+            .map(|previous| previous.pool)
+    }
+
+    /// Stop committing receipts for the allocation, while still accepting
+    /// releases of receipts borrowed from it. Returns false if the
+    /// allocation is unknown.
+    pub fn close_allocation(&mut self, allocation: &Address) -> bool {
+        match self.pools.get_mut(allocation) {
+            Some(entry) => {
+                entry.closed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the allocation, returning its pool so that the receipts can be
+    /// collected.
+    pub fn remove_allocation(&mut self, allocation: &Address) -> Option<ReceiptPool> {
+        self.pools.remove(allocation).map(|entry| entry.pool)
+    }
+
+    pub fn is_closed(&self, allocation: &Address) -> Option<bool> {
+        self.pools.get(allocation).map(|entry| entry.closed)
+    }
+
+    pub fn allocations(&self) -> impl Iterator<Item = &Address> {
+        self.pools.keys()
+    }
+
+    pub fn get(&self, allocation: &Address) -> Option<&ReceiptPool> {
+        self.pools.get(allocation).map(|entry| &entry.pool)
+    }
+
+    pub fn get_mut(&mut self, allocation: &Address) -> Option<&mut ReceiptPool> {
+        self.pools.get_mut(allocation).map(|entry| &mut entry.pool)
+    }
+
+    pub fn commit(
+        &mut self,
+        allocation: &Address,
+        signer: &(impl ReceiptSigner + ?Sized),
+        locked_fee: U256,
+    ) -> Result<BorrowedReceipt, BorrowFail> {
+        match self.pools.get_mut(allocation) {
+            Some(entry) if !entry.closed => entry.pool.commit(signer, locked_fee),
+            _ => Err(BorrowFail::NoAllocation),
+        }
+    }
+
+    /// Release a borrowed receipt into the pool of the allocation it was
+    /// committed for.
+    pub fn release(
+        &mut self,
+        borrowed: impl AsRef<[u8]>,
+        status: QueryStatus,
+    ) -> Result<(), ReleaseError> {
+        let borrowed = BorrowedReceipt::try_from(borrowed.as_ref())
+            .map_err(|_| ReleaseError::InvalidLength)?;
+        match self.pools.get_mut(borrowed.allocation_id()) {
+            Some(entry) => entry.pool.release(borrowed, status),
+            None => Err(ReleaseError::NoAllocation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::*;
+
+    #[test]
+    fn routes_by_allocation() {
+        let mut pools = ReceiptPools::new();
+        assert!(pools.add_allocation(bytes(1)));
+        assert!(pools.add_allocation(bytes(2)));
+        assert!(!pools.add_allocation(bytes(2)));
+
+        let borrow1 = pools.commit(&bytes(1), &test_signer(), 1.into()).unwrap();
+        let borrow2 = pools.commit(&bytes(2), &test_signer(), 2.into()).unwrap();
+        pools.release(&borrow1, QueryStatus::Success).unwrap();
+        pools.release(&borrow2, QueryStatus::Success).unwrap();
+
+        assert_eq!(
+            pools.get(&bytes(1)).unwrap().known_unlocked_fees(),
+            1.into()
+        );
+        assert_eq!(
+            pools.get(&bytes(2)).unwrap().known_unlocked_fees(),
+            2.into()
+        );
+    }
+
+    #[test]
+    fn unknown_allocation() {
+        let mut pools = ReceiptPools::new();
+        assert_eq!(
+            pools.commit(&bytes(1), &test_signer(), 1.into()),
+            Err(BorrowFail::NoAllocation)
+        );
+
+        let borrow = ReceiptPool::new(bytes(1))
+            .commit(&test_signer(), 1.into())
+            .unwrap();
+        assert_eq!(
+            pools.release(&borrow, QueryStatus::Success),
+            Err(ReleaseError::NoAllocation)
+        );
+        assert_eq!(
+            pools.release(&borrow.as_bytes()[1..], QueryStatus::Success),
+            Err(ReleaseError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn allocation_lifecycle() {
+        let mut pools = ReceiptPools::new();
+        pools.add_allocation(bytes(1));
+        let borrow = pools.commit(&bytes(1), &test_signer(), 3.into()).unwrap();
+
+        // Closed allocations accept releases, but not commits.
+        assert!(pools.close_allocation(&bytes(1)));
+        assert_eq!(pools.is_closed(&bytes(1)), Some(true));
+        assert_eq!(
+            pools.commit(&bytes(1), &test_signer(), 1.into()),
+            Err(BorrowFail::NoAllocation)
+        );
+        pools.release(&borrow, QueryStatus::Success).unwrap();
+
+        // Re-opening keeps the existing receipts.
+        assert!(pools.add_allocation(bytes(1)));
+        pools.commit(&bytes(1), &test_signer(), 1.into()).unwrap();
+
+        let pool = pools.remove_allocation(&bytes(1)).unwrap();
+        assert_eq!(pool.allocation, bytes(1));
+        assert_eq!(pools.allocations().count(), 0);
+        assert!(!pools.close_allocation(&bytes(1)));
+    }
+}
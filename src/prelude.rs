@@ -1,10 +1,12 @@
 pub use std::convert::TryInto as _;
-use std::{fmt, mem::size_of};
+use std::{error::Error, fmt, mem::size_of};
 
 use lazy_static::lazy_static;
 pub use primitive_types::U256;
-pub use rand::{thread_rng as rng, Rng as _};
-use secp256k1::{Message, Secp256k1, SecretKey};
+pub use rand::Rng as _;
+use secp256k1::{ecdsa, PublicKey, Secp256k1};
+
+use crate::ReceiptSigner;
 
 pub type Bytes32 = [u8; 32];
 pub type Address = [u8; 20];
@@ -39,33 +41,70 @@ pub fn to_be_bytes(value: U256) -> Bytes32 {
     result
 }
 
-#[derive(Eq, PartialEq, Debug)]
+/// The Ethereum address of the public key.
+pub fn to_address(public_key: &PublicKey) -> Address {
+    let hash = hash_bytes(&public_key.serialize_uncompressed()[1..]);
+    hash[12..].try_into().unwrap()
+}
+
+#[derive(Debug)]
 pub enum SignError {
     InvalidRecoveryId,
+    /// A failure reported by a signer which keeps its key elsewhere.
+    Signer(Box<dyn Error + Send + Sync>),
 }
 
-impl fmt::Display for SignError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("Invalid recovery ID")
+// Signer errors are compared by their messages.
+impl PartialEq for SignError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::InvalidRecoveryId, Self::InvalidRecoveryId) => true,
+            (Self::Signer(a), Self::Signer(b)) => a.to_string() == b.to_string(),
+            _ => false,
+        }
     }
 }
 
-pub fn sign(data: &[u8], signer: &SecretKey) -> Result<Signature, SignError> {
-    let message = Message::from_digest_slice(&hash_bytes(data)).unwrap();
+impl Eq for SignError {}
 
-    let signature = SECP256K1.sign_ecdsa_recoverable(&message, signer);
-    let (recovery_id, signature) = signature.serialize_compact();
-    let recovery_id = match recovery_id.to_i32() {
-        0 => 27,
-        1 => 28,
-        27 => 27,
-        28 => 28,
-        _ => return Err(SignError::InvalidRecoveryId),
-    };
+impl Error for SignError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::InvalidRecoveryId => None,
+            Self::Signer(err) => Some(&**err),
+        }
+    }
+}
 
-    let mut serialized = [0; 65];
-    serialized[..64].copy_from_slice(&signature);
-    serialized[64] = recovery_id;
+impl fmt::Display for SignError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRecoveryId => f.write_str("Invalid recovery ID"),
+            Self::Signer(_) => f.write_str("Signer error"),
+        }
+    }
+}
 
-    Ok(serialized)
+pub fn sign(data: &[u8], signer: &(impl ReceiptSigner + ?Sized)) -> Result<Signature, SignError> {
+    canonical_signature(signer.sign_digest(&hash_bytes(data))?)
+}
+
+/// Check a signature from a signer has a recovery id of 27 or 28, and
+/// normalize a high S value so that the signature isn't malleable. This
+/// holds receipts and vouchers to the checks made by strict verification,
+/// whatever the signer.
+pub(crate) fn canonical_signature(mut signature: Signature) -> Result<Signature, SignError> {
+    if !matches!(signature[64], 27 | 28) {
+        return Err(SignError::InvalidRecoveryId);
+    }
+    let mut normalized = ecdsa::Signature::from_compact(&signature[..64])
+        .map_err(|err| SignError::Signer(Box::new(err)))?;
+    normalized.normalize_s();
+    let normalized = normalized.serialize_compact();
+    if normalized != signature[..64] {
+        // Negating S negates the recovered point, which flips the recovery id.
+        signature[..64].copy_from_slice(&normalized);
+        signature[64] ^= 27 ^ 28;
+    }
+    Ok(signature)
 }
@@ -0,0 +1,271 @@
+use std::{fmt, ops::Range};
+
+use crate::prelude::*;
+
+/// The high bytes of the receipt ids minted by a pool, used to partition the
+/// receipt id space between gateway replicas which sign for the same
+/// allocation.
+///
+/// Receipt ids minted by pools with distinct prefixes of the same length
+/// can never collide. Mixing prefix lengths loses this guarantee, since a
+/// short prefix may be the start of a longer one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReceiptIdPrefix {
+    bytes: [u8; ReceiptIdPrefix::MAX_LEN],
+    len: usize,
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ReceiptIdPrefixError {
+    InvalidLength,
+}
+
+impl std::error::Error for ReceiptIdPrefixError {}
+
+impl fmt::Display for ReceiptIdPrefixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength => write!(
+                f,
+                "Receipt id prefix must be 1 to {} bytes",
+                ReceiptIdPrefix::MAX_LEN
+            ),
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ReceiptIdDerivationError {
+    AllocationMismatch,
+}
+
+impl std::error::Error for ReceiptIdDerivationError {}
+
+impl fmt::Display for ReceiptIdDerivationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AllocationMismatch => {
+                write!(f, "Receipt id derivation is for a different allocation")
+            }
+        }
+    }
+}
+
+impl ReceiptIdPrefix {
+    /// The longest allowed prefix. This leaves enough of the receipt id for
+    /// each replica to mint receipt ids without coordination.
+    pub const MAX_LEN: usize = 8;
+
+    pub fn new(prefix: &[u8]) -> Result<Self, ReceiptIdPrefixError> {
+        if prefix.is_empty() || prefix.len() > Self::MAX_LEN {
+            return Err(ReceiptIdPrefixError::InvalidLength);
+        }
+        let mut bytes = [0; Self::MAX_LEN];
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        Ok(Self {
+            bytes,
+            len: prefix.len(),
+        })
+    }
+
+    /// Extract the prefix of the given length from any receipt id, such as
+    /// to find which replica issued a receipt.
+    pub fn from_receipt_id(
+        receipt_id: &ReceiptId,
+        len: usize,
+    ) -> Result<Self, ReceiptIdPrefixError> {
+        if len > receipt_id.len() {
+            return Err(ReceiptIdPrefixError::InvalidLength);
+        }
+        Self::new(&receipt_id[..len])
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn matches(&self, receipt_id: &ReceiptId) -> bool {
+        receipt_id.starts_with(self.as_bytes())
+    }
+
+    /// Overwrite the high bytes of the receipt id with the prefix.
+    pub(crate) fn apply(&self, receipt_id: &mut ReceiptId) {
+        receipt_id[..self.len].copy_from_slice(self.as_bytes());
+    }
+}
+
+/// Derives receipt ids from a secret seed, the allocation id and a counter,
+/// so that a gateway which knows the seed and the last counter it used can
+/// regenerate every receipt id it minted for an allocation without storing
+/// them, and recognise its own receipts later.
+///
+/// The seed must be kept secret, otherwise the receipt ids a gateway will
+/// use can be predicted.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ReceiptIdDerivation {
+    seed: Bytes32,
+    allocation: Address,
+    prefix: Option<ReceiptIdPrefix>,
+}
+
+impl fmt::Debug for ReceiptIdDerivation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReceiptIdDerivation")
+            .field("allocation", &self.allocation)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReceiptIdDerivation {
+    pub fn new(seed: Bytes32, allocation: Address) -> Self {
+        Self {
+            seed,
+            allocation,
+            prefix: None,
+        }
+    }
+
+    /// Overwrite the high bytes of derived receipt ids with the prefix, so
+    /// that replicas sharing a seed still mint distinct receipt ids.
+    pub fn with_prefix(mut self, prefix: ReceiptIdPrefix) -> Self {
+        self.prefix = Some(prefix);
+        self
+    }
+
+    pub fn allocation(&self) -> &Address {
+        &self.allocation
+    }
+
+    /// The receipt id for the given counter. This is the leading bytes of
+    /// keccak256(seed, allocation_id, counter).
+    pub fn derive(&self, counter: u64) -> ReceiptId {
+        let mut message =
+            Vec::with_capacity(size_of::<Bytes32>() + size_of::<Address>() + size_of::<u64>());
+        message.extend_from_slice(&self.seed);
+        message.extend_from_slice(&self.allocation);
+        message.extend_from_slice(&counter.to_be_bytes());
+        let hash = hash_bytes(&message);
+
+        let mut receipt_id: ReceiptId = hash[..size_of::<ReceiptId>()].try_into().unwrap();
+        if let Some(prefix) = &self.prefix {
+            prefix.apply(&mut receipt_id);
+        }
+        receipt_id
+    }
+
+    /// Regenerate the receipt ids for a range of counters.
+    pub fn receipt_ids(&self, counters: Range<u64>) -> impl Iterator<Item = ReceiptId> + '_ {
+        counters.map(|counter| self.derive(counter))
+    }
+
+    /// Find the counter the receipt id was derived from, if it is in the
+    /// given range. This is a linear search over the range.
+    pub fn find(&self, receipt_id: &ReceiptId, counters: Range<u64>) -> Option<u64> {
+        counters
+            .into_iter()
+            .find(|counter| &self.derive(*counter) == receipt_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng as _};
+
+    use super::*;
+    use crate::{tests::*, ReceiptPool};
+
+    #[test]
+    fn prefix_length() {
+        assert_eq!(
+            ReceiptIdPrefix::new(&[]),
+            Err(ReceiptIdPrefixError::InvalidLength)
+        );
+        assert_eq!(
+            ReceiptIdPrefix::new(&[0; 9]),
+            Err(ReceiptIdPrefixError::InvalidLength)
+        );
+        assert_eq!(ReceiptIdPrefix::new(&[1, 2]).unwrap().as_bytes(), &[1, 2]);
+    }
+
+    #[test]
+    fn derived_ids_can_be_regenerated() {
+        let derivation = ReceiptIdDerivation::new(bytes(9), bytes(1));
+        let mut pool = ReceiptPool::new(bytes(1));
+        pool.set_receipt_id_derivation(Some(derivation.clone()))
+            .unwrap();
+        let minted: Vec<ReceiptId> = (0..5)
+            .map(|_| *pool.commit(&test_signer(), 1.into()).unwrap().receipt_id())
+            .collect();
+        assert_eq!(pool.receipt_id_counter(), 5);
+
+        let regenerated: Vec<ReceiptId> = derivation.receipt_ids(0..5).collect();
+        assert_eq!(minted, regenerated);
+        assert_eq!(derivation.find(&minted[3], 0..100), Some(3));
+        assert_eq!(derivation.find(&bytes(0), 0..100), None);
+
+        // Other seeds and allocations derive unrelated ids.
+        let other_seed = ReceiptIdDerivation::new(bytes(8), bytes(1));
+        let other_allocation = ReceiptIdDerivation::new(bytes(9), bytes(2));
+        assert_eq!(other_seed.find(&minted[3], 0..100), None);
+        assert_eq!(other_allocation.find(&minted[3], 0..100), None);
+
+        // A restored pool continues from the last counter.
+        let mut restored = ReceiptPool::restore(bytes(1), pool.snapshot()).unwrap();
+        restored
+            .set_receipt_id_derivation(Some(derivation.clone()))
+            .unwrap();
+        let borrow = restored.commit(&test_signer(), 1.into()).unwrap();
+        assert!(!minted.contains(borrow.receipt_id()));
+    }
+
+    #[test]
+    fn derivation_must_match_allocation() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let derivation = ReceiptIdDerivation::new(bytes(9), bytes(2));
+        assert_eq!(
+            pool.set_receipt_id_derivation(Some(derivation.clone())),
+            Err(ReceiptIdDerivationError::AllocationMismatch)
+        );
+        pool.commit(&test_signer(), 1.into()).unwrap();
+        assert_eq!(pool.receipt_id_counter(), 0);
+    }
+
+    #[test]
+    fn derived_ids_with_prefix() {
+        let prefix = ReceiptIdPrefix::new(&[7]).unwrap();
+        let derivation = ReceiptIdDerivation::new(bytes(9), bytes(1)).with_prefix(prefix);
+        let unprefixed = ReceiptIdDerivation::new(bytes(9), bytes(1));
+        for counter in 0..10 {
+            assert!(prefix.matches(&derivation.derive(counter)));
+            assert_eq!(
+                derivation.derive(counter)[1..],
+                unprefixed.derive(counter)[1..]
+            );
+        }
+    }
+
+    #[test]
+    fn replicas_never_collide() {
+        // Even with identical randomness, replicas mint distinct receipt ids.
+        let mint = |prefix: &[u8]| {
+            let mut pool = ReceiptPool::with_rng(bytes(1), StdRng::seed_from_u64(0));
+            pool.set_receipt_id_prefix(Some(ReceiptIdPrefix::new(prefix).unwrap()));
+            (0..10)
+                .map(|_| *pool.commit(&test_signer(), 1.into()).unwrap().receipt_id())
+                .collect::<Vec<_>>()
+        };
+        let replica_a = mint(&[0, 1]);
+        let replica_b = mint(&[0, 2]);
+
+        for (a, b) in replica_a.iter().zip(&replica_b) {
+            assert_ne!(a, b);
+            assert_eq!(a[2..], b[2..]);
+            assert_eq!(
+                ReceiptIdPrefix::from_receipt_id(a, 2).unwrap().as_bytes(),
+                &[0, 1]
+            );
+            assert!(ReceiptIdPrefix::new(&[0, 2]).unwrap().matches(b));
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    error::Error,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    Message, PublicKey,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    hex,
+    prelude::{canonical_signature, *},
+    ReceiptSigner,
+};
+
+/// A [`ReceiptSigner`] which keeps its key in a remote signing service, so
+/// that the key never enters this process.
+///
+/// Digests are sent as a JSON-RPC `eth_sign` request with the params
+/// `[address, digest]`. Unlike a wallet's `eth_sign`, the service must sign
+/// the 32 byte digest as given, without applying the EIP-191 message
+/// prefix, so that signatures are identical to those of the in-process key.
+/// Each returned signature is checked against the public key before use.
+#[derive(Debug)]
+pub struct RemoteSigner {
+    agent: ureq::Agent,
+    url: String,
+    public_key: PublicKey,
+    retries: u32,
+    backoff: Duration,
+    request_id: AtomicU64,
+}
+
+impl RemoteSigner {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Create a signer for the key with the given public key, held by the
+    /// service at the URL. By default requests time out after
+    /// [`RemoteSigner::DEFAULT_TIMEOUT`] and are not retried.
+    pub fn new(url: impl Into<String>, public_key: PublicKey) -> Self {
+        Self {
+            agent: agent(Self::DEFAULT_TIMEOUT),
+            url: url.into(),
+            public_key,
+            retries: 0,
+            backoff: Duration::ZERO,
+            request_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Set the time limit for each request, including connecting.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.agent = agent(timeout);
+        self
+    }
+
+    /// Retry requests which fail to connect, time out, or are rejected by
+    /// the server with a 5xx status. The delay between attempts doubles
+    /// after each retry, starting from `backoff`.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    fn recover(&self, digest: &Bytes32, signature: &Signature) -> Option<PublicKey> {
+        let recovery_id = RecoveryId::from_i32(i32::from(signature[64] - 27)).ok()?;
+        let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id).ok()?;
+        SECP256K1
+            .recover_ecdsa(&Message::from_digest(*digest), &signature)
+            .ok()
+    }
+
+    fn request(&self, body: &str) -> Result<String, Attempt> {
+        let response = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(body);
+        match response {
+            Ok(response) => response
+                .into_string()
+                .map_err(|err| Attempt::Retry(Box::new(err))),
+            Err(ureq::Error::Status(status, _)) if status >= 500 => {
+                Err(Attempt::Retry(format!("HTTP status {}", status).into()))
+            }
+            Err(ureq::Error::Status(status, _)) => {
+                Err(Attempt::Fail(format!("HTTP status {}", status).into()))
+            }
+            Err(ureq::Error::Transport(err)) => Err(Attempt::Retry(Box::new(err))),
+        }
+    }
+
+    fn request_with_retries(&self, body: &str) -> Result<String, SignError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            match self.request(body) {
+                Ok(response) => return Ok(response),
+                Err(Attempt::Retry(_)) if attempt < self.retries => {
+                    attempt += 1;
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                Err(Attempt::Retry(err) | Attempt::Fail(err)) => {
+                    return Err(SignError::Signer(err))
+                }
+            }
+        }
+    }
+}
+
+enum Attempt {
+    Retry(Box<dyn Error + Send + Sync>),
+    Fail(Box<dyn Error + Send + Sync>),
+}
+
+fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(timeout).build()
+}
+
+impl ReceiptSigner for RemoteSigner {
+    fn sign_digest(&self, digest: &Bytes32) -> Result<Signature, SignError> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": self.request_id.fetch_add(1, Ordering::Relaxed),
+            "method": "eth_sign",
+            "params": [to_hex(&self.address()), to_hex(digest)],
+        });
+        let response = self.request_with_retries(&request.to_string())?;
+
+        let response: Value =
+            serde_json::from_str(&response).map_err(|err| SignError::Signer(Box::new(err)))?;
+        if let Some(err) = response.get("error") {
+            return Err(SignError::Signer(err.to_string().into()));
+        }
+        let mut signature: Signature = response
+            .get("result")
+            .and_then(Value::as_str)
+            .and_then(hex::decode)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| SignError::Signer("Invalid signature in response".into()))?;
+
+        // Services differ on whether v is 0/1 or 27/28.
+        signature[64] = match signature[64] {
+            0 | 27 => 27,
+            1 | 28 => 28,
+            _ => return Err(SignError::InvalidRecoveryId),
+        };
+        let mut signature = canonical_signature(signature)?;
+
+        // Don't trust the service to have signed with the expected key, or
+        // to have computed v correctly. Receipts are verified by recovering
+        // the signer, so a signature with the wrong v is corrected once and
+        // otherwise rejected.
+        for _ in 0..2 {
+            if self.recover(digest, &signature) == Some(self.public_key) {
+                return Ok(signature);
+            }
+            signature[64] ^= 27 ^ 28;
+        }
+        Err(SignError::Signer(
+            "Signature does not match the signer".into(),
+        ))
+    }
+
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead as _, BufReader, Read as _, Write as _},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{receipts_to_voucher, tests::*, ReceiptPool};
+
+    // A stand-in for a remote signing service. The handler is given the
+    // request number and the JSON-RPC request, and returns the HTTP status
+    // and body of the response.
+    fn serve(handler: impl Fn(usize, Value) -> (u16, String) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let request = read_request(&stream);
+                let (status, body) = handler(i, request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    fn read_request(stream: &TcpStream) -> Value {
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn sign_request(request: &Value, signer: &impl ReceiptSigner) -> String {
+        let params = request["params"].as_array().unwrap();
+        assert_eq!(params[0], to_hex(&test_signer().address()));
+        let digest: Bytes32 = hex::decode(params[1].as_str().unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = signer.sign_digest(&digest).unwrap();
+        json!({ "jsonrpc": "2.0", "id": request["id"], "result": to_hex(&signature) }).to_string()
+    }
+
+    fn remote_signer(url: String) -> RemoteSigner {
+        RemoteSigner::new(url, ReceiptSigner::public_key(&test_signer()))
+            .with_timeout(Duration::from_millis(500))
+    }
+
+    #[test]
+    fn remote_vouchers_are_identical() {
+        let signer = remote_signer(serve(|_, request| {
+            (200, sign_request(&request, &test_signer()))
+        }));
+
+        let mut pool = ReceiptPool::new(bytes(1));
+        let borrow = pool.commit(&signer, 5.into()).unwrap();
+        assert_eq!(
+            borrow.signature(),
+            &test_signer()
+                .sign_digest(&hash_bytes(borrow.signed_message()))
+                .unwrap()
+        );
+
+        let allocation_signer = ReceiptSigner::public_key(&test_signer());
+        let remote =
+            receipts_to_voucher(&bytes(1), &allocation_signer, &signer, borrow.receipt()).unwrap();
+        let local = receipts_to_voucher(
+            &bytes(1),
+            &allocation_signer,
+            &test_signer(),
+            borrow.receipt(),
+        )
+        .unwrap();
+        assert_eq!(remote, local);
+    }
+
+    #[test]
+    fn retries_server_errors() {
+        let url = serve(|i, request| match i {
+            0 | 1 => (503, String::new()),
+            _ => (200, sign_request(&request, &test_signer())),
+        });
+        let digest = hash_bytes(b"message");
+
+        let signer = remote_signer(url.clone()).with_retries(1, Duration::from_millis(1));
+        assert!(matches!(
+            signer.sign_digest(&digest),
+            Err(SignError::Signer(_))
+        ));
+        let signer = remote_signer(url).with_retries(1, Duration::from_millis(1));
+        assert_eq!(
+            signer.sign_digest(&digest),
+            test_signer().sign_digest(&digest)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let digest = hash_bytes(b"message");
+
+        let error = remote_signer(serve(|_, request| {
+            let response = json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": { "code": -32000, "message": "locked" },
+            });
+            (200, response.to_string())
+        }));
+        assert!(matches!(
+            error.sign_digest(&digest),
+            Err(SignError::Signer(_))
+        ));
+
+        // A signature by some other key.
+        let wrong_key = remote_signer(serve(|_, request| {
+            let other = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+            (200, sign_request(&request, &other))
+        }));
+        assert!(matches!(
+            wrong_key.sign_digest(&digest),
+            Err(SignError::Signer(_))
+        ));
+    }
+
+    #[test]
+    fn corrects_recovery_byte() {
+        let wrong_parity = TestSigner::default().with_tamper(|mut signature| {
+            signature[64] ^= 27 ^ 28;
+            signature
+        });
+        let signer = remote_signer(serve(move |_, request| {
+            (200, sign_request(&request, &wrong_parity))
+        }));
+        let digest = hash_bytes(b"message");
+        assert_eq!(
+            signer.sign_digest(&digest),
+            test_signer().sign_digest(&digest)
+        );
+    }
+
+    #[test]
+    fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let signer = RemoteSigner::new(url, ReceiptSigner::public_key(&test_signer()))
+            .with_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            signer.sign_digest(&hash_bytes(b"message")),
+            Err(SignError::Signer(_))
+        ));
+        drop(listener);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{any::Any, fmt};
+
+use rand::{Rng as _, RngCore};
+
+use crate::PooledReceipt;
+
+/// Chooses which cached receipt a [`ReceiptPool`](crate::ReceiptPool)
+/// commits next. This controls how value is spread across receipt ids.
+///
+/// Pools compare their strategies by type.
+pub trait ReceiptSelection: fmt::Debug + Send + Any {
+    /// Return the index of the receipt to commit. The receipts are never
+    /// empty. They are ordered from least to most recently released if
+    /// [`ReceiptSelection::uses_release_order`] is true, and are otherwise
+    /// in no particular order. The commit fails with
+    /// [`BorrowFail::InvalidSelection`](crate::BorrowFail::InvalidSelection)
+    /// if the index is out of bounds.
+    fn select(&mut self, receipts: &[PooledReceipt], rng: &mut dyn RngCore) -> usize;
+
+    /// Whether the pool must keep receipts in release order. Otherwise the
+    /// pool may reorder receipts, which makes taking one O(1).
+    fn uses_release_order(&self) -> bool {
+        false
+    }
+}
+
+/// Pick any receipt with equal probability. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UniformRandom;
+
+impl ReceiptSelection for UniformRandom {
+    fn select(&mut self, receipts: &[PooledReceipt], rng: &mut dyn RngCore) -> usize {
+        rng.gen_range(0..receipts.len())
+    }
+}
+
+/// Pick the receipt with the lowest unlocked fee, which keeps the value
+/// riding on each receipt even.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LowestUnlockedFee;
+
+impl ReceiptSelection for LowestUnlockedFee {
+    fn select(&mut self, receipts: &[PooledReceipt], _: &mut dyn RngCore) -> usize {
+        receipts
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, receipt)| receipt.unlocked_fee)
+            .map(|(index, _)| index)
+            .unwrap()
+    }
+}
+
+/// Pick the receipt with the highest unlocked fee, which concentrates value
+/// in few receipts so that vouchers are cheaper to create.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HighestUnlockedFee;
+
+impl ReceiptSelection for HighestUnlockedFee {
+    fn select(&mut self, receipts: &[PooledReceipt], _: &mut dyn RngCore) -> usize {
+        receipts
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, receipt)| receipt.unlocked_fee)
+            .map(|(index, _)| index)
+            .unwrap()
+    }
+}
+
+/// Pick the receipt which has been in the pool the longest.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LeastRecentlyUsed;
+
+impl ReceiptSelection for LeastRecentlyUsed {
+    fn select(&mut self, _: &[PooledReceipt], _: &mut dyn RngCore) -> usize {
+        0
+    }
+
+    fn uses_release_order(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng as _};
+
+    use super::*;
+    use crate::{prelude::*, tests::*, BorrowFail, QueryStatus, ReceiptPool};
+
+    // Create a pool holding receipts with unlocked fees of 3, 1, 2 released
+    // in that order. Returns the receipt ids in release order.
+    fn test_pool(selection: impl ReceiptSelection + 'static) -> (ReceiptPool, Vec<ReceiptId>) {
+        let mut pool = ReceiptPool::with_rng(bytes(1), StdRng::seed_from_u64(0));
+        pool.set_selection(selection);
+        let borrows: Vec<_> = [3, 1, 2]
+            .into_iter()
+            .map(|fee| pool.commit(&test_signer(), fee.into()).unwrap())
+            .collect();
+        for borrow in &borrows {
+            pool.release(borrow, QueryStatus::Success).unwrap();
+        }
+        let ids = borrows.iter().map(|b| *b.receipt_id()).collect();
+        (pool, ids)
+    }
+
+    #[track_caller]
+    fn assert_selects(selection: impl ReceiptSelection + 'static, expected: usize) {
+        let (mut pool, ids) = test_pool(selection);
+        let borrow = pool.commit(&test_signer(), 1.into()).unwrap();
+        assert_eq!(borrow.receipt_id(), &ids[expected]);
+    }
+
+    #[test]
+    fn lowest_unlocked_fee() {
+        assert_selects(LowestUnlockedFee, 1);
+    }
+
+    #[test]
+    fn highest_unlocked_fee() {
+        assert_selects(HighestUnlockedFee, 0);
+    }
+
+    #[test]
+    fn least_recently_used() {
+        let (mut pool, ids) = test_pool(LeastRecentlyUsed);
+        for id in ids.iter().chain(&ids) {
+            let borrow = pool.commit(&test_signer(), 1.into()).unwrap();
+            assert_eq!(borrow.receipt_id(), id);
+            pool.release(&borrow, QueryStatus::Success).unwrap();
+        }
+    }
+
+    #[test]
+    fn uniform_random() {
+        let (mut pool, ids) = test_pool(UniformRandom);
+        let mut counts = [0; 3];
+        for _ in 0..300 {
+            let borrow = pool.commit(&test_signer(), 1.into()).unwrap();
+            let index = ids.iter().position(|id| id == borrow.receipt_id()).unwrap();
+            counts[index] += 1;
+            pool.release(&borrow, QueryStatus::Failure).unwrap();
+        }
+        // Each receipt is chosen about a third of the time.
+        assert!(
+            counts.iter().all(|count| (70..130).contains(count)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_selection() {
+        #[derive(Debug)]
+        struct PastTheEnd;
+
+        impl ReceiptSelection for PastTheEnd {
+            fn select(&mut self, receipts: &[PooledReceipt], _: &mut dyn RngCore) -> usize {
+                receipts.len()
+            }
+        }
+
+        let (mut pool, _) = test_pool(PastTheEnd);
+        assert_eq!(
+            pool.commit(&test_signer(), 1.into()),
+            Err(BorrowFail::InvalidSelection)
+        );
+        let stats = pool.stats();
+        assert_eq!((stats.cached_receipts, stats.borrowed_receipts), (3, 0));
+    }
+
+    #[test]
+    fn pools_compare_strategies_by_type() {
+        let (pool, _) = test_pool(LowestUnlockedFee);
+        let (mut other, _) = test_pool(LowestUnlockedFee);
+        assert_eq!(pool, other);
+        other.set_selection(HighestUnlockedFee);
+        assert_ne!(pool, other);
+    }
+}
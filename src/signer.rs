@@ -0,0 +1,208 @@
+use std::fmt;
+
+use secp256k1::{
+    ecdsa::{self, RecoverableSignature, RecoveryId},
+    Message, PublicKey, SecretKey,
+};
+
+use crate::prelude::*;
+
+/// A key which signs receipts and vouchers.
+///
+/// The in-process [`SecretKey`] is the simplest implementation. Implement
+/// this to keep the key elsewhere, such as in a KMS, an HSM or a remote
+/// signing service.
+pub trait ReceiptSigner {
+    /// Sign the keccak256 digest of a message. The signature is
+    /// [r, s, v] with the recovery id `v` as 27 or 28.
+    fn sign_digest(&self, digest: &Bytes32) -> Result<Signature, SignError>;
+
+    fn public_key(&self) -> PublicKey;
+
+    /// The Ethereum address of the signer.
+    fn address(&self) -> Address {
+        to_address(&self.public_key())
+    }
+}
+
+impl ReceiptSigner for SecretKey {
+    fn sign_digest(&self, digest: &Bytes32) -> Result<Signature, SignError> {
+        let message = Message::from_digest(*digest);
+
+        let signature = SECP256K1.sign_ecdsa_recoverable(&message, self);
+        let (recovery_id, signature) = signature.serialize_compact();
+        let recovery_id = match recovery_id.to_i32() {
+            0 => 27,
+            1 => 28,
+            27 => 27,
+            28 => 28,
+            _ => return Err(SignError::InvalidRecoveryId),
+        };
+
+        let mut serialized = [0; 65];
+        serialized[..64].copy_from_slice(&signature);
+        serialized[64] = recovery_id;
+
+        Ok(serialized)
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey::from_secret_key(&SECP256K1, self)
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SignatureError {
+    /// The recovery id `v` is not 27 or 28.
+    InvalidRecoveryId,
+    /// The signature has a high S value.
+    MalleableSignature,
+    /// The signature can't be parsed, or no signer can be recovered from it.
+    InvalidSignature,
+}
+
+impl std::error::Error for SignatureError {}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRecoveryId => write!(f, "Signature has an invalid recovery byte"),
+            Self::MalleableSignature => write!(f, "Signature has a high S value"),
+            Self::InvalidSignature => write!(f, "Invalid signature"),
+        }
+    }
+}
+
+/// Recover the address which signed the digest, like the EVM's `ecrecover`.
+/// The recovery id `v` must be 27 or 28.
+pub fn recover_address(digest: &Bytes32, signature: &Signature) -> Result<Address, SignatureError> {
+    Ok(to_address(&recover_public_key(digest, signature)?))
+}
+
+pub(crate) fn recover_public_key(
+    digest: &Bytes32,
+    signature: &Signature,
+) -> Result<PublicKey, SignatureError> {
+    let recovery_id = match signature[64] {
+        27 => RecoveryId::from_i32(0),
+        28 => RecoveryId::from_i32(1),
+        _ => return Err(SignatureError::InvalidRecoveryId),
+    }
+    .unwrap();
+    let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    SECP256K1
+        .recover_ecdsa(&Message::from_digest(*digest), &signature)
+        .map_err(|_| SignatureError::InvalidSignature)
+}
+
+/// Check that the signature has a recovery id of 27 or 28 and a low S value,
+/// as required of signatures which are verified on chain.
+pub(crate) fn validate_signature(signature: &Signature) -> Result<(), SignatureError> {
+    if !matches!(signature[64], 27 | 28) {
+        return Err(SignatureError::InvalidRecoveryId);
+    }
+    let signature = ecdsa::Signature::from_compact(&signature[..64])
+        .map_err(|_| SignatureError::InvalidSignature)?;
+    let mut normalized = signature;
+    normalized.normalize_s();
+    if normalized != signature {
+        return Err(SignatureError::MalleableSignature);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use rand::{rngs::StdRng, SeedableRng as _};
+
+    use super::*;
+    use crate::{receipts_to_voucher, tests::*, FeeCounter, ReceiptPool};
+
+    #[test]
+    fn address() {
+        let address: Address = [
+            0xc6, 0x11, 0x27, 0xcd, 0xfb, 0x53, 0x80, 0xdf, 0x42, 0x14, 0xb0, 0x20, 0x0b, 0x9a,
+            0x07, 0xc7, 0xc4, 0x9d, 0x34, 0xf9,
+        ];
+        assert_eq!(test_signer().address(), address);
+    }
+
+    #[test]
+    fn recovers_address() {
+        let digest = hash_bytes(b"message");
+        let mut signature = test_signer().sign_digest(&digest).unwrap();
+        assert_eq!(
+            recover_address(&digest, &signature),
+            Ok(test_signer().address())
+        );
+
+        signature[64] ^= 27 ^ 28;
+        assert_ne!(
+            recover_address(&digest, &signature),
+            Ok(test_signer().address())
+        );
+        for v in [0, 1, 29] {
+            signature[64] = v;
+            assert_eq!(
+                recover_address(&digest, &signature),
+                Err(SignatureError::InvalidRecoveryId)
+            );
+        }
+    }
+
+    #[test]
+    fn signatures_are_canonical() {
+        // A signer which doesn't normalize S.
+        let signer = TestSigner::default().with_tamper(malleate);
+
+        let pool = || ReceiptPool::with_rng(bytes(1), StdRng::seed_from_u64(0));
+        let expected = pool().commit(&test_signer(), 1.into()).unwrap();
+        let borrow = pool().commit(&signer, 1.into()).unwrap();
+        assert_eq!(borrow.signature(), expected.signature());
+    }
+
+    #[test]
+    fn custom_signer() {
+        let signer = TestSigner::default();
+        let dyn_signer: &dyn ReceiptSigner = &signer;
+
+        let mut pool = ReceiptPool::new(bytes(1));
+        let borrow = pool.commit(dyn_signer, 1.into()).unwrap();
+        let voucher = receipts_to_voucher(
+            &bytes(1),
+            &signer.public_key(),
+            dyn_signer,
+            borrow.receipt(),
+        )
+        .unwrap();
+        let expected = receipts_to_voucher(
+            &bytes(1),
+            &signer.public_key(),
+            &test_signer(),
+            borrow.receipt(),
+        )
+        .unwrap();
+
+        assert_eq!(voucher, expected);
+        assert_eq!(signer.signatures(), 2);
+    }
+
+    #[test]
+    fn signer_errors_keep_their_source() {
+        let mut pool = ReceiptPool::new(bytes(1));
+        let err = pool
+            .commit(&TestSigner::default().failing(), 1.into())
+            .unwrap_err();
+        let sign_error = err.source().unwrap();
+        let io_error = sign_error.source().unwrap();
+        assert_eq!(io_error.to_string(), "timed out");
+        assert!(io_error.downcast_ref::<std::io::Error>().is_some());
+        // The borrow is returned, and not counted, when signing fails.
+        let stats = pool.stats();
+        assert_eq!(stats.borrowed_receipts, 0);
+        assert_eq!(stats.lifetime.committed, FeeCounter::default());
+    }
+}
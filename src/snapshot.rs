@@ -0,0 +1,296 @@
+use std::fmt;
+
+use crate::{pool::PooledReceipt, prelude::*};
+
+// Binary layout of an encoded snapshot:
+// [magic, version, allocation, receipt count, receipts.., borrow count, borrows..,
+//  retired count, retired receipts.., receipt id counter, checksum]
+// The checksum is the keccak hash of everything which precedes it.
+const MAGIC: [u8; 4] = *b"RCPT";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + size_of::<u8>() + size_of::<Address>();
+const RECEIPT_LEN: usize = size_of::<ReceiptId>() + size_of::<U256>();
+const BORROW_LEN: usize = RECEIPT_LEN + size_of::<U256>();
+const CHECKSUM_LEN: usize = size_of::<Bytes32>();
+
+/// The persistable state of a [`ReceiptPool`](crate::ReceiptPool).
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PoolSnapshot {
+    pub allocation: Address,
+    /// Receipts which were available to be borrowed.
+    pub receipts: Vec<PooledReceipt>,
+    /// Receipts which were borrowed and not yet released.
+    pub borrowed: Vec<BorrowSnapshot>,
+    /// Receipts which were retired, and not yet collected.
+    pub retired: Vec<PooledReceipt>,
+    /// The counter from which the next derived receipt id would be minted.
+    pub receipt_id_counter: u64,
+}
+
+/// A receipt which was borrowed at the time the snapshot was taken.
+#[derive(Eq, PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BorrowSnapshot {
+    pub receipt_id: ReceiptId,
+    /// The fee unlocked by the receipt before it was borrowed.
+    pub unlocked_fee: U256,
+    /// The fee added to the receipt when it was borrowed.
+    pub locked_fee: U256,
+}
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum SnapshotError {
+    InvalidData,
+    UnsupportedVersion(u8),
+    ChecksumMismatch,
+    AllocationMismatch,
+    DuplicateReceiptId,
+    /// The fees of the receipts sum past the largest U256.
+    FeeOverflow,
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidData => write!(f, "Invalid snapshot data"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot version: {}", version)
+            }
+            Self::ChecksumMismatch => write!(f, "Snapshot checksum mismatch"),
+            Self::AllocationMismatch => write!(f, "Snapshot is for a different allocation"),
+            Self::DuplicateReceiptId => write!(f, "Snapshot contains duplicate receipt ids"),
+            Self::FeeOverflow => write!(f, "Snapshot fees overflow"),
+        }
+    }
+}
+
+impl PoolSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            HEADER_LEN
+                + 3 * size_of::<u32>()
+                + (self.receipts.len() + self.retired.len()) * RECEIPT_LEN
+                + self.borrowed.len() * BORROW_LEN
+                + size_of::<u64>()
+                + CHECKSUM_LEN,
+        );
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.allocation);
+
+        write_receipts(&mut bytes, &self.receipts);
+
+        bytes.extend_from_slice(&(self.borrowed.len() as u32).to_be_bytes());
+        for borrow in &self.borrowed {
+            bytes.extend_from_slice(&borrow.receipt_id);
+            bytes.extend_from_slice(&to_be_bytes(borrow.unlocked_fee));
+            bytes.extend_from_slice(&to_be_bytes(borrow.locked_fee));
+        }
+
+        write_receipts(&mut bytes, &self.retired);
+        bytes.extend_from_slice(&self.receipt_id_counter.to_be_bytes());
+
+        let checksum = hash_bytes(&bytes);
+        bytes.extend_from_slice(&checksum);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN || bytes[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::InvalidData);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if hash_bytes(body) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let mut reader = Reader {
+            data: &body[MAGIC.len()..],
+        };
+        let version = reader.take::<1>()?[0];
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let allocation = reader.take::<20>()?;
+
+        let receipts = reader.take_receipts()?;
+
+        let borrow_count = reader.take_len()?;
+        let mut borrowed = Vec::new();
+        for _ in 0..borrow_count {
+            borrowed.push(BorrowSnapshot {
+                receipt_id: reader.take::<15>()?,
+                unlocked_fee: U256::from_big_endian(&reader.take::<32>()?),
+                locked_fee: U256::from_big_endian(&reader.take::<32>()?),
+            });
+        }
+
+        let retired = reader.take_receipts()?;
+        let receipt_id_counter = u64::from_be_bytes(reader.take::<8>()?);
+
+        if !reader.data.is_empty() {
+            return Err(SnapshotError::InvalidData);
+        }
+
+        Ok(Self {
+            allocation,
+            receipts,
+            borrowed,
+            retired,
+            receipt_id_counter,
+        })
+    }
+}
+
+fn write_receipts(bytes: &mut Vec<u8>, receipts: &[PooledReceipt]) {
+    bytes.extend_from_slice(&(receipts.len() as u32).to_be_bytes());
+    for receipt in receipts {
+        bytes.extend_from_slice(&receipt.receipt_id);
+        bytes.extend_from_slice(&to_be_bytes(receipt.unlocked_fee));
+    }
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        if self.data.len() < N {
+            return Err(SnapshotError::InvalidData);
+        }
+        let (head, tail) = self.data.split_at(N);
+        self.data = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn take_len(&mut self) -> Result<usize, SnapshotError> {
+        Ok(u32::from_be_bytes(self.take::<4>()?) as usize)
+    }
+
+    fn take_receipts(&mut self) -> Result<Vec<PooledReceipt>, SnapshotError> {
+        let count = self.take_len()?;
+        let mut receipts = Vec::new();
+        for _ in 0..count {
+            receipts.push(PooledReceipt {
+                receipt_id: self.take::<15>()?,
+                unlocked_fee: U256::from_big_endian(&self.take::<32>()?),
+            });
+        }
+        Ok(receipts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::*, BorrowedReceipt, QueryStatus, ReceiptPool};
+
+    fn test_pool() -> (ReceiptPool, Vec<BorrowedReceipt>) {
+        let mut pool = ReceiptPool::new(bytes(3));
+        pool.set_max_receipt_fee(Some(5.into()));
+        let mut borrows = Vec::new();
+        for fee in 1..=5 {
+            borrows.push(pool.commit(&test_signer(), fee.into()).unwrap());
+        }
+        for borrow in borrows.drain(2..) {
+            pool.release(&borrow, QueryStatus::Success).unwrap();
+        }
+        (pool, borrows)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut pool, borrows) = test_pool();
+        let snapshot = pool.snapshot();
+        assert_eq!(snapshot.receipts.len(), 2);
+        assert_eq!(snapshot.borrowed.len(), 2);
+        assert_eq!(snapshot.retired.len(), 1);
+
+        let decoded = PoolSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(decoded, snapshot);
+
+        let mut restored = ReceiptPool::restore(bytes(3), decoded).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        let (stats, restored_stats) = (pool.stats(), restored.stats());
+        assert_eq!(restored_stats.unlocked_fees, stats.unlocked_fees);
+        assert_eq!(restored_stats.locked_fees, stats.locked_fees);
+
+        // Outstanding borrows can still be released into the restored pool.
+        for borrow in &borrows {
+            pool.release(borrow, QueryStatus::Success).unwrap();
+            restored.release(borrow, QueryStatus::Success).unwrap();
+        }
+        assert_eq!(restored.stats().unlocked_fees, 15.into());
+        assert_eq!(restored.snapshot(), pool.snapshot());
+    }
+
+    #[test]
+    fn rejects_other_allocation() {
+        let (pool, _) = test_pool();
+        let snapshot = PoolSnapshot::from_bytes(&pool.snapshot().to_bytes()).unwrap();
+        assert_eq!(
+            ReceiptPool::restore(bytes(4), snapshot),
+            Err(SnapshotError::AllocationMismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_data() {
+        let (pool, _) = test_pool();
+        let encoded = pool.snapshot().to_bytes();
+
+        for i in 0..encoded.len() {
+            let mut corrupted = encoded.clone();
+            corrupted[i] ^= 1;
+            assert!(PoolSnapshot::from_bytes(&corrupted).is_err());
+        }
+        for len in 0..encoded.len() {
+            assert!(PoolSnapshot::from_bytes(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_receipts() {
+        let (pool, _) = test_pool();
+        let mut snapshot = pool.snapshot();
+        snapshot.receipts.push(snapshot.receipts[0].clone());
+        let snapshot = PoolSnapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(
+            ReceiptPool::restore(bytes(3), snapshot),
+            Err(SnapshotError::DuplicateReceiptId)
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_fees() {
+        let (pool, _) = test_pool();
+        let mut snapshot = pool.snapshot();
+        snapshot.receipts[0].unlocked_fee = U256::MAX;
+        assert_eq!(
+            ReceiptPool::restore(bytes(3), snapshot.clone()),
+            Err(SnapshotError::FeeOverflow)
+        );
+        snapshot.receipts[0].unlocked_fee = 0.into();
+        snapshot.borrowed[0].locked_fee = U256::MAX;
+        assert_eq!(
+            ReceiptPool::restore(bytes(3), snapshot),
+            Err(SnapshotError::FeeOverflow)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let (pool, _) = test_pool();
+        let snapshot = pool.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<PoolSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+}
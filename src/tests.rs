@@ -1,4 +1,8 @@
-use std::{convert::TryFrom, time::Instant};
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
 use secp256k1::{PublicKey, SecretKey};
 
@@ -21,11 +25,90 @@ pub fn make_receipt() {
 
     println!("Receipt 0: value 5");
     let commit0 = pool.commit(&test_signer(), U256::from(5)).unwrap();
-    debug_hex(&commit0);
+    debug_hex(commit0.as_bytes());
 
     println!("Receipt 1: value 8");
     let commit1 = pool.commit(&test_signer(), U256::from(8)).unwrap();
-    debug_hex(&commit1);
+    debug_hex(commit1.as_bytes());
+}
+
+/// Stands in for a signer which holds the key of [`test_signer`] elsewhere,
+/// such as in a KMS or a remote signing service. Signing may be delayed,
+/// and each signature altered, or signing may fail.
+pub struct TestSigner {
+    delay: Duration,
+    tamper: fn(Signature) -> Signature,
+    fail: bool,
+    signatures: AtomicUsize,
+}
+
+impl Default for TestSigner {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            tamper: |signature| signature,
+            fail: false,
+            signatures: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl TestSigner {
+    /// Wait before each signature. The async signer waits on the runtime,
+    /// and the sync signer blocks the thread.
+    #[cfg(feature = "async")]
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn with_tamper(mut self, tamper: fn(Signature) -> Signature) -> Self {
+        self.tamper = tamper;
+        self
+    }
+
+    /// Fail to sign with an `io::Error` as the source.
+    pub fn failing(mut self) -> Self {
+        self.fail = true;
+        self
+    }
+
+    /// The number of signatures requested so far.
+    pub fn signatures(&self) -> usize {
+        self.signatures.load(Ordering::Relaxed)
+    }
+
+    fn sign(&self, digest: &Bytes32) -> Result<Signature, SignError> {
+        self.signatures.fetch_add(1, Ordering::Relaxed);
+        if self.fail {
+            let err = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+            return Err(SignError::Signer(Box::new(err)));
+        }
+        Ok((self.tamper)(test_signer().sign_digest(digest)?))
+    }
+}
+
+impl ReceiptSigner for TestSigner {
+    fn sign_digest(&self, digest: &Bytes32) -> Result<Signature, SignError> {
+        std::thread::sleep(self.delay);
+        self.sign(digest)
+    }
+
+    fn public_key(&self) -> PublicKey {
+        ReceiptSigner::public_key(&test_signer())
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncReceiptSigner for TestSigner {
+    async fn sign_digest(&self, digest: &Bytes32) -> Result<Signature, SignError> {
+        tokio::time::sleep(self.delay).await;
+        self.sign(digest)
+    }
+
+    fn public_key(&self) -> PublicKey {
+        ReceiptSigner::public_key(&test_signer())
+    }
 }
 
 pub fn test_signer() -> SecretKey {
@@ -45,7 +128,7 @@ pub fn test_signer() -> SecretKey {
 fn speed() {
     let mut pool = ReceiptPool::new(bytes(0));
 
-    let mut borrows = Vec::<Vec<u8>>::new();
+    let mut borrows = Vec::<BorrowedReceipt>::new();
 
     let start = Instant::now();
 
@@ -55,7 +138,7 @@ fn speed() {
             borrows.push(commitment)
         }
         while let Some(borrow) = borrows.pop() {
-            pool.release(&borrow, QueryStatus::Success)
+            pool.release(&borrow, QueryStatus::Success).unwrap();
         }
     }
 
@@ -70,7 +153,7 @@ fn attempt_to_double_collect_with_partial_voucher_rejects() {
 
     // Create a bunch of receipts
     let mut pool = ReceiptPool::new(allocation_id);
-    let mut borrows = Vec::<Vec<u8>>::new();
+    let mut borrows = Vec::<BorrowedReceipt>::new();
     for _ in 0..10 {
         let fee = U256::from(1);
         let commitment = pool.commit(&test_signer(), fee).unwrap();
@@ -107,11 +190,11 @@ fn vouchers() {
 
     // Create a bunch of receipts
     let mut pool = ReceiptPool::new(allocation_id);
-    let mut borrows = Vec::<Vec<u8>>::new();
+    let mut borrows = Vec::<BorrowedReceipt>::new();
     let mut fees = U256::zero();
     for i in 2..10 {
         for borrow in borrows.drain(..) {
-            pool.release(&borrow, QueryStatus::Success);
+            pool.release(&borrow, QueryStatus::Success).unwrap();
         }
         for _ in 0..i {
             let fee = U256::from(1);
@@ -137,6 +220,204 @@ fn vouchers() {
     assert_eq!(&voucher.fees, &fees);
 }
 
+#[test]
+fn vouchers_by_allocation_address() {
+    // The allocation id is the address of the allocation signer.
+    let allocation_id = test_signer().address();
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 10);
+
+    let expected = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    for signer in [
+        ExpectedSigner::AllocationId,
+        ExpectedSigner::Address(allocation_id),
+    ] {
+        let voucher =
+            receipts_to_voucher(&allocation_id, signer, &test_signer(), &receipts).unwrap();
+        assert_eq!(voucher, expected);
+    }
+
+    let err = receipts_to_voucher(&allocation_id, &bytes::<20>(2), &test_signer(), &receipts);
+    assert_eq!(err, Err(VoucherError::InvalidSignature));
+
+    // Receipts for an allocation id which is not the signer's address.
+    let receipts = create_receipts(bytes(1), 10);
+    let err = receipts_to_partial_voucher(
+        &bytes(1),
+        ExpectedSigner::default(),
+        &test_signer(),
+        &receipts,
+    );
+    assert_eq!(err.err(), Some(VoucherError::InvalidSignature));
+}
+
+#[test]
+fn strict_signature_validation() {
+    let allocation_id = test_signer().address();
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 5);
+    let expected = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    let lenient = |signer| ReceiptVerification {
+        signer,
+        validation: SignatureValidation::Lenient,
+    };
+
+    let mut bad_recovery_byte = receipts.clone();
+    bad_recovery_byte[112 * 2 + 111] = 0;
+    let err = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &bad_recovery_byte,
+    );
+    assert_eq!(err, Err(VoucherError::InvalidRecoveryByte { index: 2 }));
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        lenient(ExpectedSigner::PublicKey(allocation_signer)),
+        &test_signer(),
+        &bad_recovery_byte,
+    );
+    assert_eq!(voucher, Ok(expected.clone()));
+    // Strictly, the recovery byte must recover the signer.
+    let mut wrong_recovery_byte = receipts.clone();
+    wrong_recovery_byte[112 * 2 + 111] ^= 27 ^ 28;
+    let err = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &wrong_recovery_byte,
+    );
+    assert_eq!(err, Err(VoucherError::InvalidSignature));
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        lenient(ExpectedSigner::PublicKey(allocation_signer)),
+        &test_signer(),
+        &wrong_recovery_byte,
+    );
+    assert_eq!(voucher, Ok(expected.clone()));
+    // Recovering the signer needs the recovery byte, even when lenient.
+    let err = receipts_to_voucher(
+        &allocation_id,
+        lenient(ExpectedSigner::AllocationId),
+        &test_signer(),
+        &bad_recovery_byte,
+    );
+    assert_eq!(err, Err(VoucherError::InvalidRecoveryByte { index: 2 }));
+
+    let mut high_s = receipts.clone();
+    let signature = &mut high_s[112 * 3 + 47..112 * 4];
+    let malleated = malleate(signature.try_into().unwrap());
+    signature.copy_from_slice(&malleated);
+    let err = receipts_to_voucher(
+        &allocation_id,
+        ExpectedSigner::AllocationId,
+        &test_signer(),
+        &high_s,
+    );
+    assert_eq!(err, Err(VoucherError::MalleableSignature { index: 3 }));
+    // Leniently, a high S value is accepted by every kind of signer.
+    for signer in [
+        ExpectedSigner::AllocationId,
+        ExpectedSigner::PublicKey(allocation_signer),
+    ] {
+        let voucher = receipts_to_voucher(&allocation_id, lenient(signer), &test_signer(), &high_s);
+        assert_eq!(voucher, Ok(expected.clone()));
+    }
+
+    let mut partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112)
+        .map(|receipt| {
+            receipts_to_partial_voucher(&allocation_id, &allocation_signer, &test_signer(), receipt)
+                .unwrap()
+        })
+        .collect();
+    partial_vouchers[1].voucher.signature[64] = 1;
+    let err = combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers);
+    assert_eq!(err, Err(VoucherError::InvalidRecoveryByte { index: 1 }));
+}
+
+#[test]
+fn verify_vouchers() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let voucher_signer = test_signer().address();
+    let receipts = create_receipts(allocation_id, 10);
+
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(verify_voucher(&voucher, &voucher_signer), Ok(()));
+    assert_eq!(
+        verify_voucher(&voucher, &bytes(2)),
+        Err(VoucherError::InvalidSignature)
+    );
+    let mut tampered = voucher.clone();
+    tampered.fees += U256::one();
+    assert_eq!(
+        verify_voucher(&tampered, &voucher_signer),
+        Err(VoucherError::InvalidSignature)
+    );
+    let mut malleated = voucher.clone();
+    malleated.signature = malleate(voucher.signature);
+    assert_eq!(
+        verify_voucher(&malleated, &voucher_signer),
+        Err(VoucherError::InvalidVoucherSignature(
+            SignatureError::MalleableSignature
+        ))
+    );
+    let mut bad_recovery_byte = voucher.clone();
+    bad_recovery_byte.signature[64] = 1;
+    assert_eq!(
+        verify_voucher(&bad_recovery_byte, &voucher_signer),
+        Err(VoucherError::InvalidVoucherSignature(
+            SignatureError::InvalidRecoveryId
+        ))
+    );
+
+    let partial_voucher = receipts_to_partial_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(
+        verify_partial_voucher(&partial_voucher, &allocation_id, &voucher_signer),
+        Ok(())
+    );
+    assert_eq!(
+        verify_partial_voucher(&partial_voucher, &bytes(2), &voucher_signer),
+        Err(VoucherError::AllocationMismatch)
+    );
+    // A partial voucher is not a valid voucher, or the reverse.
+    assert_eq!(
+        verify_voucher(&partial_voucher.voucher, &voucher_signer),
+        Err(VoucherError::InvalidSignature)
+    );
+    let mut widened = partial_voucher.clone();
+    widened.receipt_id_max = [0xff; 15];
+    assert_eq!(
+        verify_partial_voucher(&widened, &allocation_id, &voucher_signer),
+        Err(VoucherError::InvalidSignature)
+    );
+}
+
 #[test]
 #[ignore = "Benchmark"]
 fn vouchers_speed() {
@@ -224,9 +505,319 @@ fn partial_vouchers_combine() {
     assert_eq!(oneshot_receipt, combined_voucher);
 }
 
-fn create_receipts(allocation_id: Address, count: usize) -> Vec<u8> {
+#[test]
+fn partial_vouchers_combine_with_rotated_signer() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let previous_signer = SecretKey::from_slice(&[1; 32]).unwrap();
+    let receipts = create_receipts(allocation_id, 10);
+
+    // Half of the partial vouchers were signed before the voucher signer was
+    // rotated.
+    let partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112 * 5)
+        .zip([&previous_signer, &test_signer()])
+        .map(|(receipts, voucher_signer)| {
+            receipts_to_partial_voucher(
+                &allocation_id,
+                &allocation_signer,
+                voucher_signer,
+                receipts,
+            )
+            .unwrap()
+        })
+        .collect();
+
+    let trusted_signers = [
+        ExpectedSigner::from(&ReceiptSigner::public_key(&test_signer())),
+        ExpectedSigner::from(&previous_signer.address()),
+    ];
+    let combined = combine_partial_vouchers_with_signers(
+        &allocation_id,
+        &trusted_signers,
+        &test_signer(),
+        &partial_vouchers,
+    )
+    .unwrap();
+    let oneshot = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(combined, oneshot);
+
+    // Without the previous signer, its partial voucher isn't trusted.
+    assert_eq!(
+        combine_partial_vouchers_with_signers(
+            &allocation_id,
+            &trusted_signers[..1],
+            &test_signer(),
+            &partial_vouchers,
+        ),
+        Err(VoucherError::InvalidSignature)
+    );
+    assert_eq!(
+        combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers),
+        Err(VoucherError::InvalidSignature)
+    );
+    // Neither voucher signer is the allocation signer.
+    assert_eq!(
+        combine_partial_vouchers_with_signers(
+            &allocation_id,
+            &[ExpectedSigner::AllocationId],
+            &test_signer(),
+            &partial_vouchers,
+        ),
+        Err(VoucherError::InvalidSignature)
+    );
+
+    // A single trusted key gives the same voucher as before.
+    let current = &partial_vouchers[1..];
+    assert_eq!(
+        combine_partial_vouchers_with_signers(
+            &allocation_id,
+            &trusted_signers[..1],
+            &test_signer(),
+            current,
+        ),
+        combine_partial_vouchers(&allocation_id, &test_signer(), current)
+    );
+}
+
+#[test]
+fn partial_vouchers_merge() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 16);
+
+    // Roll up pairs of partial vouchers until only two are left.
+    let mut partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112 * 2)
+        .map(|receipts| {
+            receipts_to_partial_voucher(
+                &allocation_id,
+                &allocation_signer,
+                &test_signer(),
+                receipts,
+            )
+            .unwrap()
+        })
+        .collect();
+    while partial_vouchers.len() > 2 {
+        partial_vouchers = partial_vouchers
+            .chunks(2)
+            .map(|pair| merge_partial_vouchers(&allocation_id, &test_signer(), pair).unwrap())
+            .collect();
+    }
+    let expected = receipts_to_partial_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts[..112 * 8],
+    )
+    .unwrap();
+    assert_eq!(partial_vouchers[0].voucher, expected.voucher);
+    assert_eq!(
+        (
+            partial_vouchers[0].receipt_id_min,
+            partial_vouchers[0].receipt_id_max
+        ),
+        (expected.receipt_id_min, expected.receipt_id_max)
+    );
+
+    let combined =
+        combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers).unwrap();
+    let oneshot = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(combined, oneshot);
+
+    let overlapping = [expected.clone(), expected];
+    assert_eq!(
+        merge_partial_vouchers(&allocation_id, &test_signer(), &overlapping).err(),
+        Some(VoucherError::UnorderedPartialVouchers)
+    );
+}
+
+#[test]
+fn fee_overflow() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let mut pool = ReceiptPool::new(allocation_id);
+    let max = pool.commit(&test_signer(), U256::MAX).unwrap();
+    // The pool won't commit past the largest U256, so commit the second
+    // receipt from another pool for the same allocation.
+    let one = ReceiptPool::new(allocation_id)
+        .commit(&test_signer(), U256::one())
+        .unwrap();
+
+    let receipts = receipts_from_borrows(vec![max.clone()]);
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(voucher.fees, U256::MAX);
+
+    let receipts = receipts_from_borrows(vec![max, one]);
+    assert_eq!(
+        receipts_to_voucher(
+            &allocation_id,
+            &allocation_signer,
+            &test_signer(),
+            &receipts
+        ),
+        Err(VoucherError::FeeOverflow)
+    );
+
+    let partial_vouchers: Vec<PartialVoucher> = receipts
+        .chunks(112)
+        .map(|receipt| {
+            receipts_to_partial_voucher(&allocation_id, &allocation_signer, &test_signer(), receipt)
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(
+        combine_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers),
+        Err(VoucherError::FeeOverflow)
+    );
+    assert_eq!(
+        merge_partial_vouchers(&allocation_id, &test_signer(), &partial_vouchers).err(),
+        Some(VoucherError::FeeOverflow)
+    );
+}
+
+#[test]
+fn receipts_report_lists_every_fault() {
+    let allocation_id = bytes(1);
+    let allocation_signer = PublicKey::from_secret_key(&SECP256K1, &test_signer());
+    let receipts = create_receipts(allocation_id, 7);
+
+    let report = receipts_report(&allocation_id, &allocation_signer, &receipts);
+    assert!(report.is_valid());
+    assert_eq!(report.receipts, 7);
+    let voucher = receipts_to_voucher(
+        &allocation_id,
+        &allocation_signer,
+        &test_signer(),
+        &receipts,
+    )
+    .unwrap();
+    assert_eq!(report.valid_fees, voucher.fees);
+
+    let chunks: Vec<&[u8]> = receipts.chunks(112).collect();
+    let mut tampered = chunks[3].to_vec();
+    tampered[31] ^= 1;
+    let mut bad_recovery = chunks[4].to_vec();
+    bad_recovery[111] = 0;
+    let mut malleated = chunks[6].to_vec();
+    let signature = malleate(malleated[47..].try_into().unwrap());
+    malleated[47..].copy_from_slice(&signature);
+    // A valid receipt with the highest fee, ordered after the others.
+    let mut pool = ReceiptPool::new(allocation_id);
+    pool.set_receipt_id_prefix(Some(ReceiptIdPrefix::new(&[0xff; 8]).unwrap()));
+    let overflow = pool.commit(&test_signer(), U256::MAX).unwrap();
+    let overflow = overflow.receipt();
+    let data = [
+        chunks[0],
+        chunks[2],
+        chunks[1],
+        chunks[2],
+        &tampered,
+        &bad_recovery,
+        chunks[5],
+        &malleated,
+        overflow,
+        &chunks[5][..50],
+    ]
+    .concat();
+    let id = |chunk: &[u8]| Some(chunk[32..47].try_into().unwrap());
+
+    let report = receipts_report(&allocation_id, &allocation_signer, &data);
+    assert!(!report.is_valid());
+    assert_eq!(report.receipts, 10);
+    assert_eq!(
+        report.faults,
+        vec![
+            FaultyReceipt {
+                index: 2,
+                receipt_id: id(chunks[1]),
+                fault: ReceiptFault::Unordered,
+            },
+            FaultyReceipt {
+                index: 3,
+                receipt_id: id(chunks[2]),
+                fault: ReceiptFault::DuplicateId,
+            },
+            FaultyReceipt {
+                index: 4,
+                receipt_id: id(chunks[3]),
+                fault: ReceiptFault::InvalidSignature,
+            },
+            FaultyReceipt {
+                index: 5,
+                receipt_id: id(chunks[4]),
+                fault: ReceiptFault::InvalidRecoveryByte,
+            },
+            FaultyReceipt {
+                index: 7,
+                receipt_id: id(chunks[6]),
+                fault: ReceiptFault::MalleableSignature,
+            },
+            FaultyReceipt {
+                index: 8,
+                receipt_id: id(overflow),
+                fault: ReceiptFault::FeeOverflow,
+            },
+            FaultyReceipt {
+                index: 9,
+                receipt_id: None,
+                fault: ReceiptFault::InvalidLength,
+            },
+        ]
+    );
+    assert_eq!(report.valid_fees, U256::from(3));
+
+    // Receipts are only accepted if the report is valid.
+    let fault_data = [
+        data,
+        [chunks[0], &tampered].concat(),
+        [chunks[0], &malleated].concat(),
+        [chunks[0], overflow].concat(),
+        [chunks[0], &chunks[1][..50]].concat(),
+    ];
+    for data in &fault_data {
+        let report = receipts_report(&allocation_id, &allocation_signer, data);
+        assert!(!report.is_valid());
+        let voucher = receipts_to_voucher(&allocation_id, &allocation_signer, &test_signer(), data);
+        assert!(voucher.is_err());
+    }
+}
+
+/// The other valid signature for the same message, with S negated.
+pub fn malleate(mut signature: Signature) -> Signature {
+    let n = U256::from_big_endian(&[
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xfe, 0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36,
+        0x41, 0x41,
+    ]);
+    let s = U256::from_big_endian(&signature[32..64]);
+    signature[32..64].copy_from_slice(&to_be_bytes(n - s));
+    signature[64] ^= 27 ^ 28;
+    signature
+}
+
+pub fn create_receipts(allocation_id: Address, count: usize) -> Vec<u8> {
     let mut pool = ReceiptPool::new(allocation_id);
-    let mut borrows = Vec::<Vec<u8>>::new();
+    let mut borrows = Vec::<BorrowedReceipt>::new();
     for _ in 1..=count {
         let commitment = pool.commit(&test_signer(), U256::from(1)).unwrap();
         borrows.push(commitment);
@@ -234,13 +825,13 @@ fn create_receipts(allocation_id: Address, count: usize) -> Vec<u8> {
     receipts_from_borrows(borrows)
 }
 
-fn receipts_from_borrows(mut borrows: Vec<Vec<u8>>) -> Vec<u8> {
+pub fn receipts_from_borrows(mut borrows: Vec<BorrowedReceipt>) -> Vec<u8> {
     let mut receipts = Vec::with_capacity(112 * borrows.len());
     // Sort by receipt id
-    borrows.sort_by_key(|b| ReceiptId::try_from(&b[52..67]).unwrap());
+    borrows.sort_by_key(|b| *b.receipt_id());
     // Serialize
     for borrow in borrows {
-        receipts.extend_from_slice(&borrow[20..132]);
+        receipts.extend_from_slice(borrow.receipt());
     }
     receipts
 }
@@ -1,10 +1,15 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
 use itertools::Itertools as _;
-use secp256k1::{ecdsa, Message, PublicKey, SecretKey};
+use secp256k1::{ecdsa, Message, PublicKey};
 use tiny_keccak::{Hasher, Keccak};
 
-use crate::prelude::*;
+use crate::{
+    prelude::*,
+    recover_address,
+    signer::{recover_public_key, validate_signature},
+    ReceiptSigner, SignatureError,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum VoucherError {
@@ -13,10 +18,34 @@ pub enum VoucherError {
     UnorderedReceipts,
     UnorderedPartialVouchers,
     NoValue,
-    InvalidRecoveryId,
+    Signer(SignError),
+    /// The signature of the receipt or partial voucher at the index has a
+    /// recovery id other than 27 or 28.
+    InvalidRecoveryByte {
+        index: usize,
+    },
+    /// The signature of the receipt or partial voucher at the index has a
+    /// high S value.
+    MalleableSignature {
+        index: usize,
+    },
+    AllocationMismatch,
+    /// The signature of the voucher or partial voucher being verified is
+    /// invalid.
+    InvalidVoucherSignature(SignatureError),
+    /// The total fees exceed the largest U256.
+    FeeOverflow,
 }
 
-impl std::error::Error for VoucherError {}
+impl std::error::Error for VoucherError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Signer(err) => Some(err),
+            Self::InvalidVoucherSignature(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for VoucherError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -26,16 +55,23 @@ impl fmt::Display for VoucherError {
             Self::UnorderedReceipts => write!(f, "Unordered receipts"),
             Self::UnorderedPartialVouchers => write!(f, "Unordered partial vouchers"),
             Self::NoValue => write!(f, "Receipts have no value"),
-            Self::InvalidRecoveryId => SignError::InvalidRecoveryId.fmt(f),
+            Self::Signer(_) => write!(f, "Failed to sign the voucher"),
+            Self::InvalidRecoveryByte { index } => {
+                write!(f, "Signature {} has an invalid recovery byte", index)
+            }
+            Self::MalleableSignature { index } => {
+                write!(f, "Signature {} has a high S value", index)
+            }
+            Self::AllocationMismatch => write!(f, "Voucher is for another allocation"),
+            Self::InvalidVoucherSignature(_) => write!(f, "Invalid voucher signature"),
+            Self::FeeOverflow => write!(f, "Total fees overflow"),
         }
     }
 }
 
 impl From<SignError> for VoucherError {
     fn from(err: SignError) -> Self {
-        match err {
-            SignError::InvalidRecoveryId => Self::InvalidRecoveryId,
-        }
+        Self::Signer(err)
     }
 }
 
@@ -56,8 +92,8 @@ struct Receipt<'r> {
 }
 
 impl Receipts<'_> {
-    fn new(data: &[u8]) -> Result<Receipts, VoucherError> {
-        if data.len() % SIZE != 0 {
+    fn new(data: &[u8]) -> Result<Receipts<'_>, VoucherError> {
+        if !data.len().is_multiple_of(SIZE) {
             return Err(VoucherError::InvalidData);
         }
         Ok(Receipts { data, index: 0 })
@@ -80,6 +116,75 @@ impl<'r> Iterator for Receipts<'r> {
     }
 }
 
+/// Who must have signed the receipts for an allocation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpectedSigner {
+    /// Recover the signer of each receipt and compare it to the public key
+    /// of the allocation signer. With lenient validation, each signature is
+    /// verified against the public key instead, ignoring the recovery byte.
+    PublicKey(PublicKey),
+    /// Recover the signer of each receipt and compare its address.
+    Address(Address),
+    /// Recover the signer of each receipt and compare its address to the
+    /// allocation id, since the allocation id is the address of the
+    /// allocation signer.
+    #[default]
+    AllocationId,
+}
+
+impl From<&PublicKey> for ExpectedSigner {
+    fn from(public_key: &PublicKey) -> Self {
+        Self::PublicKey(*public_key)
+    }
+}
+
+impl From<&Address> for ExpectedSigner {
+    fn from(address: &Address) -> Self {
+        Self::Address(*address)
+    }
+}
+
+/// How strictly receipt signatures are validated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureValidation {
+    /// Reject signatures with a recovery id other than 27 or 28, or with a
+    /// high S value. An on-chain verifier may reject these, and a high S
+    /// signature can be malleated into a second valid signature for the
+    /// same receipt.
+    #[default]
+    Strict,
+    /// Only check that each signature was made by the allocation signer.
+    Lenient,
+}
+
+/// How the receipts for an allocation are verified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiptVerification {
+    pub signer: ExpectedSigner,
+    pub validation: SignatureValidation,
+}
+
+impl From<ExpectedSigner> for ReceiptVerification {
+    fn from(signer: ExpectedSigner) -> Self {
+        Self {
+            signer,
+            validation: SignatureValidation::default(),
+        }
+    }
+}
+
+impl From<&PublicKey> for ReceiptVerification {
+    fn from(public_key: &PublicKey) -> Self {
+        ExpectedSigner::from(public_key).into()
+    }
+}
+
+impl From<&Address> for ReceiptVerification {
+    fn from(address: &Address) -> Self {
+        ExpectedSigner::from(address).into()
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Voucher {
     pub allocation_id: Address,
@@ -100,17 +205,18 @@ pub struct PartialVoucher {
 /// One exception is that they may be the same signer. They are allowed to be different
 /// in case we want to rotate the voucher_signer and keep old receipts intact. Having
 /// them be the same signer is ok only because they sign messages of different lengths.
-
+///
+/// The allocation signer may be given as a public key, or as an address to
+/// compare with the signer recovered from each receipt. Signatures are
+/// validated strictly unless [`SignatureValidation::Lenient`] is given.
 pub fn receipts_to_voucher(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
-    voucher_signer: &SecretKey,
+    allocation_signer: impl Into<ReceiptVerification>,
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
     data: &[u8],
 ) -> Result<Voucher, VoucherError> {
-    let fees = verify_receipts(allocation_id, allocation_signer, data)?;
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
+    let fees = verify_receipts(allocation_id, allocation_signer.into(), data)?;
+    let message = voucher_message(allocation_id, fees);
     Ok(Voucher {
         allocation_id: *allocation_id,
         fees,
@@ -120,18 +226,13 @@ pub fn receipts_to_voucher(
 
 pub fn receipts_to_partial_voucher(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
-    voucher_signer: &SecretKey,
+    allocation_signer: impl Into<ReceiptVerification>,
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
     data: &[u8],
 ) -> Result<PartialVoucher, VoucherError> {
-    let fees = verify_receipts(allocation_id, allocation_signer, data)?;
-    let receipt_id_min = *Receipts::new(data)?.next().unwrap().id;
-    let receipt_id_max = *Receipts::new(data)?.last().unwrap().id;
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
-    message.extend_from_slice(&receipt_id_min);
-    message.extend_from_slice(&receipt_id_max);
+    let fees = verify_receipts(allocation_id, allocation_signer.into(), data)?;
+    let (receipt_id_min, receipt_id_max) = receipt_id_bounds(data)?;
+    let message = partial_voucher_message(allocation_id, fees, &receipt_id_min, &receipt_id_max);
     Ok(PartialVoucher {
         voucher: Voucher {
             allocation_id: *allocation_id,
@@ -143,9 +244,85 @@ pub fn receipts_to_partial_voucher(
     })
 }
 
-fn verify_receipts(
+/// Check the voucher is signed by the voucher signer, as the contract does
+/// when the voucher is redeemed.
+pub fn verify_voucher(voucher: &Voucher, voucher_signer: &Address) -> Result<(), VoucherError> {
+    if voucher.fees == U256::zero() {
+        return Err(VoucherError::NoValue);
+    }
+    let message = voucher_message(&voucher.allocation_id, voucher.fees);
+    verify_signer(&message, &voucher.signature, voucher_signer)
+}
+
+/// Check the partial voucher is for the allocation and is signed by the
+/// voucher signer.
+pub fn verify_partial_voucher(
+    partial_voucher: &PartialVoucher,
+    allocation_id: &Address,
+    voucher_signer: &Address,
+) -> Result<(), VoucherError> {
+    let voucher = &partial_voucher.voucher;
+    if &voucher.allocation_id != allocation_id {
+        return Err(VoucherError::AllocationMismatch);
+    }
+    if partial_voucher.receipt_id_min > partial_voucher.receipt_id_max {
+        return Err(VoucherError::UnorderedPartialVouchers);
+    }
+    if voucher.fees == U256::zero() {
+        return Err(VoucherError::NoValue);
+    }
+    let message = partial_voucher_message(
+        allocation_id,
+        voucher.fees,
+        &partial_voucher.receipt_id_min,
+        &partial_voucher.receipt_id_max,
+    );
+    verify_signer(&message, &voucher.signature, voucher_signer)
+}
+
+fn verify_signer(
+    message: &[u8],
+    signature: &Signature,
+    signer: &Address,
+) -> Result<(), VoucherError> {
+    validate_signature(signature).map_err(VoucherError::InvalidVoucherSignature)?;
+    let recovered = recover_address(&hash_bytes(message), signature)
+        .map_err(VoucherError::InvalidVoucherSignature)?;
+    if &recovered != signer {
+        return Err(VoucherError::InvalidSignature);
+    }
+    Ok(())
+}
+
+pub(crate) fn voucher_message(allocation_id: &Address, fees: U256) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(allocation_id);
+    message.extend_from_slice(&to_be_bytes(fees));
+    message
+}
+
+pub(crate) fn partial_voucher_message(
+    allocation_id: &Address,
+    fees: U256,
+    receipt_id_min: &ReceiptId,
+    receipt_id_max: &ReceiptId,
+) -> Vec<u8> {
+    let mut message = voucher_message(allocation_id, fees);
+    message.extend_from_slice(receipt_id_min);
+    message.extend_from_slice(receipt_id_max);
+    message
+}
+
+/// The first and last receipt ids of verified receipts.
+pub(crate) fn receipt_id_bounds(data: &[u8]) -> Result<(ReceiptId, ReceiptId), VoucherError> {
+    let receipt_id_min = *Receipts::new(data)?.next().ok_or(VoucherError::NoValue)?.id;
+    let receipt_id_max = *Receipts::new(data)?.last().ok_or(VoucherError::NoValue)?.id;
+    Ok((receipt_id_min, receipt_id_max))
+}
+
+pub(crate) fn verify_receipts(
     allocation_id: &Address,
-    allocation_signer: &PublicKey,
+    verification: ReceiptVerification,
     data: &[u8],
 ) -> Result<U256, VoucherError> {
     // Verify the receipts are sorted and ascending.
@@ -159,28 +336,11 @@ fn verify_receipts(
     }
 
     // Verify signatures
-    for receipt in Receipts::new(data)? {
-        // Create the signed message from the receipt data.
-        // Allocationid is "untrusted" and kept separate from the receipt data.
-        // This also de-duplicates it in the message.
-        let mut hasher = Keccak::v256();
-        hasher.update(allocation_id);
-        hasher.update(&to_be_bytes(receipt.fees));
-        hasher.update(receipt.id);
-        let mut message = Bytes32::default();
-        hasher.finalize(&mut message);
-
-        let message = Message::from_digest_slice(&message).unwrap();
-        let signature = ecdsa::Signature::from_compact(&receipt.signature[..64])
-            .map_err(|_| VoucherError::InvalidData)?;
-        SECP256K1
-            .verify_ecdsa(&message, &signature, allocation_signer)
-            .map_err(|_| VoucherError::InvalidSignature)?;
-    }
-
-    let fees = Receipts::new(data)?
-        .map(|receipt| receipt.fees)
-        .fold(U256::zero(), |sum, fees| sum.saturating_add(fees));
+    for (index, receipt) in Receipts::new(data)?.enumerate() {
+        verify_receipt_signature(allocation_id, &verification, index, &receipt)?;
+    }
+
+    let fees = sum_fees(Receipts::new(data)?.map(|receipt| receipt.fees))?;
     // The contract will revert if this is 0
     if fees == U256::zero() {
         return Err(VoucherError::NoValue);
@@ -188,11 +348,260 @@ fn verify_receipts(
     Ok(fees)
 }
 
+fn verify_receipt_signature(
+    allocation_id: &Address,
+    verification: &ReceiptVerification,
+    index: usize,
+    receipt: &Receipt,
+) -> Result<(), VoucherError> {
+    let strict = verification.validation == SignatureValidation::Strict;
+    if strict {
+        validate_signature(receipt.signature).map_err(at_index(index))?;
+    }
+
+    // Create the signed message from the receipt data.
+    // Allocationid is "untrusted" and kept separate from the receipt data.
+    // This also de-duplicates it in the message.
+    let mut hasher = Keccak::v256();
+    hasher.update(allocation_id);
+    hasher.update(&to_be_bytes(receipt.fees));
+    hasher.update(receipt.id);
+    let mut message = Bytes32::default();
+    hasher.finalize(&mut message);
+
+    let valid = match verification.signer {
+        // Checking the signature against the public key ignores the recovery
+        // byte. Strictly, the recovery byte must recover the signer, as it
+        // does on chain. A high S value is accepted as it is by recovery,
+        // so it is normalized for libsecp256k1, which only verifies low S.
+        ExpectedSigner::PublicKey(public_key) if !strict => {
+            let message = Message::from_digest(message);
+            let mut signature = ecdsa::Signature::from_compact(&receipt.signature[..64])
+                .map_err(|_| VoucherError::InvalidData)?;
+            signature.normalize_s();
+            SECP256K1
+                .verify_ecdsa(&message, &signature, &public_key)
+                .is_ok()
+        }
+        ExpectedSigner::PublicKey(public_key) => {
+            recover_public_key(&message, receipt.signature).map_err(at_index(index))? == public_key
+        }
+        ExpectedSigner::Address(address) => {
+            let public_key =
+                recover_public_key(&message, receipt.signature).map_err(at_index(index))?;
+            to_address(&public_key) == address
+        }
+        ExpectedSigner::AllocationId => {
+            let public_key =
+                recover_public_key(&message, receipt.signature).map_err(at_index(index))?;
+            to_address(&public_key) == *allocation_id
+        }
+    };
+    if !valid {
+        return Err(VoucherError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Why a receipt failed verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReceiptFault {
+    /// The data ends with a truncated receipt.
+    InvalidLength,
+    /// The receipt id is lower than that of an earlier receipt.
+    Unordered,
+    /// The receipt id is the same as that of an earlier receipt.
+    DuplicateId,
+    /// The signature is not by the expected signer, or can't be parsed.
+    InvalidSignature,
+    /// The signature has a recovery id other than 27 or 28.
+    InvalidRecoveryByte,
+    /// The signature has a high S value.
+    MalleableSignature,
+    /// Adding the fee to the total fees of the earlier valid receipts
+    /// overflows.
+    FeeOverflow,
+}
+
+/// A receipt which failed verification. A receipt with several faults is
+/// listed once for each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FaultyReceipt {
+    /// The position of the receipt in the data.
+    pub index: usize,
+    /// `None` for a truncated receipt, which may not contain a whole id.
+    pub receipt_id: Option<ReceiptId>,
+    pub fault: ReceiptFault,
+}
+
+/// The result of checking every receipt, from [`receipts_report`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceiptReport {
+    /// The number of receipts, including a truncated receipt.
+    pub receipts: usize,
+    pub faults: Vec<FaultyReceipt>,
+    /// The total fees of the receipts without faults.
+    pub valid_fees: U256,
+}
+
+impl ReceiptReport {
+    /// Whether `receipts_to_voucher` would accept the receipts.
+    pub fn is_valid(&self) -> bool {
+        self.faults.is_empty() && self.valid_fees != U256::zero()
+    }
+}
+
+/// Check every receipt as `receipts_to_voucher` would, but rather than
+/// stopping at the first error, report every faulty receipt.
+pub fn receipts_report(
+    allocation_id: &Address,
+    allocation_signer: impl Into<ReceiptVerification>,
+    data: &[u8],
+) -> ReceiptReport {
+    let verification = allocation_signer.into();
+    let mut report = ReceiptReport::default();
+    let whole = data.len() - data.len() % SIZE;
+    let mut seen = HashSet::new();
+    let mut highest_id: Option<&ReceiptId> = None;
+    for (index, receipt) in Receipts::new(&data[..whole]).unwrap().enumerate() {
+        report.receipts += 1;
+        let mut fault = |fault| {
+            report.faults.push(FaultyReceipt {
+                index,
+                receipt_id: Some(*receipt.id),
+                fault,
+            })
+        };
+        let mut valid = true;
+
+        if !seen.insert(receipt.id) {
+            fault(ReceiptFault::DuplicateId);
+            valid = false;
+        } else if highest_id.is_some_and(|highest| receipt.id < highest) {
+            fault(ReceiptFault::Unordered);
+            valid = false;
+        }
+        highest_id = highest_id.max(Some(receipt.id));
+
+        if let Err(err) = verify_receipt_signature(allocation_id, &verification, index, &receipt) {
+            fault(match err {
+                VoucherError::InvalidRecoveryByte { .. } => ReceiptFault::InvalidRecoveryByte,
+                VoucherError::MalleableSignature { .. } => ReceiptFault::MalleableSignature,
+                _ => ReceiptFault::InvalidSignature,
+            });
+            valid = false;
+        }
+
+        if valid {
+            match report.valid_fees.checked_add(receipt.fees) {
+                Some(fees) => report.valid_fees = fees,
+                None => fault(ReceiptFault::FeeOverflow),
+            }
+        }
+    }
+    if whole != data.len() {
+        report.faults.push(FaultyReceipt {
+            index: report.receipts,
+            receipt_id: None,
+            fault: ReceiptFault::InvalidLength,
+        });
+        report.receipts += 1;
+    }
+    report
+}
+
 pub fn combine_partial_vouchers(
     allocation_id: &Address,
-    voucher_signer: &SecretKey,
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
     partial_vouchers: &[PartialVoucher],
 ) -> Result<Voucher, VoucherError> {
+    combine_partial_vouchers_with_signers(
+        allocation_id,
+        &[ExpectedSigner::PublicKey(voucher_signer.public_key())],
+        voucher_signer,
+        partial_vouchers,
+    )
+}
+
+/// Combine partial vouchers signed by any of the trusted signers, such as
+/// the current and previous voucher signers, into a voucher signed by the
+/// voucher signer. [`ExpectedSigner::AllocationId`] trusts the allocation
+/// signer.
+pub fn combine_partial_vouchers_with_signers(
+    allocation_id: &Address,
+    trusted_signers: &[ExpectedSigner],
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
+    partial_vouchers: &[PartialVoucher],
+) -> Result<Voucher, VoucherError> {
+    let fees = verify_partial_vouchers(allocation_id, trusted_signers, partial_vouchers)?;
+
+    // Create signature for complete voucher
+    let message = voucher_message(allocation_id, fees);
+    let signature = sign(&message, voucher_signer)?;
+
+    Ok(Voucher {
+        allocation_id: *allocation_id,
+        fees,
+        signature,
+    })
+}
+
+/// Merge ordered, non-overlapping partial vouchers into one partial voucher
+/// spanning their receipt ids, so that partial vouchers can be rolled up as
+/// they are collected. Combining merged partial vouchers gives the same
+/// voucher as combining the originals.
+pub fn merge_partial_vouchers(
+    allocation_id: &Address,
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
+    partial_vouchers: &[PartialVoucher],
+) -> Result<PartialVoucher, VoucherError> {
+    merge_partial_vouchers_with_signers(
+        allocation_id,
+        &[ExpectedSigner::PublicKey(voucher_signer.public_key())],
+        voucher_signer,
+        partial_vouchers,
+    )
+}
+
+/// Like [`merge_partial_vouchers`], accepting partial vouchers signed by any
+/// of the trusted signers.
+pub fn merge_partial_vouchers_with_signers(
+    allocation_id: &Address,
+    trusted_signers: &[ExpectedSigner],
+    voucher_signer: &(impl ReceiptSigner + ?Sized),
+    partial_vouchers: &[PartialVoucher],
+) -> Result<PartialVoucher, VoucherError> {
+    let fees = verify_partial_vouchers(allocation_id, trusted_signers, partial_vouchers)?;
+    let (receipt_id_min, receipt_id_max) = partial_voucher_bounds(partial_vouchers);
+    let message = partial_voucher_message(allocation_id, fees, &receipt_id_min, &receipt_id_max);
+    Ok(PartialVoucher {
+        voucher: Voucher {
+            allocation_id: *allocation_id,
+            fees,
+            signature: sign(&message, voucher_signer)?,
+        },
+        receipt_id_min,
+        receipt_id_max,
+    })
+}
+
+/// The receipt id range spanned by verified partial vouchers.
+pub(crate) fn partial_voucher_bounds(
+    partial_vouchers: &[PartialVoucher],
+) -> (ReceiptId, ReceiptId) {
+    (
+        partial_vouchers.first().unwrap().receipt_id_min,
+        partial_vouchers.last().unwrap().receipt_id_max,
+    )
+}
+
+/// Check the partial vouchers are ordered and each signed by one of the
+/// trusted signers, and return their total fees.
+pub(crate) fn verify_partial_vouchers(
+    allocation_id: &Address,
+    trusted_signers: &[ExpectedSigner],
+    partial_vouchers: &[PartialVoucher],
+) -> Result<U256, VoucherError> {
     if partial_vouchers.is_empty() {
         return Err(VoucherError::NoValue);
     }
@@ -214,41 +623,49 @@ pub fn combine_partial_vouchers(
     }
 
     // Verify signatures
-    let partial_voucher_signer = PublicKey::from_secret_key(&SECP256K1, voucher_signer);
-    for partial_voucher in partial_vouchers {
-        let mut hasher = Keccak::v256();
-        hasher.update(allocation_id);
-        hasher.update(&to_be_bytes(partial_voucher.voucher.fees));
-        hasher.update(&partial_voucher.receipt_id_min);
-        hasher.update(&partial_voucher.receipt_id_max);
-        let mut message = Bytes32::default();
-        hasher.finalize(&mut message);
-
-        let message = Message::from_digest_slice(&message).unwrap();
-        let signature = ecdsa::Signature::from_compact(&partial_voucher.voucher.signature[..64])
-            .map_err(|_| VoucherError::InvalidData)?;
-        SECP256K1
-            .verify_ecdsa(&message, &signature, &partial_voucher_signer)
-            .map_err(|_| VoucherError::InvalidSignature)?;
-    }
-
-    let fees = partial_vouchers
-        .iter()
-        .map(|pv| pv.voucher.fees)
-        .fold(U256::zero(), |sum, fees| sum.saturating_add(fees));
+    for (index, partial_voucher) in partial_vouchers.iter().enumerate() {
+        let signature = &partial_voucher.voucher.signature;
+        validate_signature(signature).map_err(at_index(index))?;
+
+        let message = hash_bytes(&partial_voucher_message(
+            allocation_id,
+            partial_voucher.voucher.fees,
+            &partial_voucher.receipt_id_min,
+            &partial_voucher.receipt_id_max,
+        ));
+        let public_key = recover_public_key(&message, signature).map_err(at_index(index))?;
+        let address = to_address(&public_key);
+        let trusted = trusted_signers
+            .iter()
+            .any(|trusted_signer| match trusted_signer {
+                ExpectedSigner::PublicKey(trusted) => trusted == &public_key,
+                ExpectedSigner::Address(trusted) => trusted == &address,
+                ExpectedSigner::AllocationId => &address == allocation_id,
+            });
+        if !trusted {
+            return Err(VoucherError::InvalidSignature);
+        }
+    }
+
+    let fees = sum_fees(partial_vouchers.iter().map(|pv| pv.voucher.fees))?;
     if fees == U256::zero() {
         return Err(VoucherError::NoValue);
     }
 
-    // Create signature for complete voucher
-    let mut message = Vec::new();
-    message.extend_from_slice(allocation_id);
-    message.extend_from_slice(&to_be_bytes(fees));
-    let signature = sign(&message, voucher_signer)?;
+    Ok(fees)
+}
 
-    Ok(Voucher {
-        allocation_id: *allocation_id,
-        fees,
-        signature,
-    })
+fn sum_fees(mut fees: impl Iterator<Item = U256>) -> Result<U256, VoucherError> {
+    fees.try_fold(U256::zero(), |sum, fees| sum.checked_add(fees))
+        .ok_or(VoucherError::FeeOverflow)
+}
+
+/// Attribute a signature error to the receipt or partial voucher at the
+/// index.
+fn at_index(index: usize) -> impl Fn(SignatureError) -> VoucherError {
+    move |err| match err {
+        SignatureError::InvalidRecoveryId => VoucherError::InvalidRecoveryByte { index },
+        SignatureError::MalleableSignature => VoucherError::MalleableSignature { index },
+        SignatureError::InvalidSignature => VoucherError::InvalidSignature,
+    }
 }